@@ -1,12 +1,18 @@
 use super::Specifier;
 use colored::*;
 use crossbeam::channel::{unbounded, Receiver};
+use expectation_shared::config::Config;
 use expectation_shared::filesystem::*;
-use expectation_shared::Result as EResult;
-use crate::promote::promote;
-use serde_json;
-use std::io::Result as IoResult;
+use expectation_shared::history::{self, Action, History};
+use expectation_shared::index;
+use expectation_shared::{Result as EResult, ResultKind};
+use expectation_shared::promote::promote_results;
+use crate::output::PromotionSummary;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Error as IoError, ErrorKind, Result as IoResult};
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
 use std::thread::spawn;
 
@@ -21,7 +27,13 @@ fn get_listener() -> IoResult<TcpListener> {
     TcpListener::bind("localhost:{9100}")
 }
 
-pub fn tcp_listen() -> IoResult<(String, Receiver<(String, Vec<EResult>)>)> {
+/// A test's name, the directory its snapshots are in, and its results.
+type Message = (String, PathBuf, Vec<EResult>);
+
+/// Where `cargo expect run` records its results, in the snapshot root.
+const LAST_RUN_FILE: &str = ".last-run";
+
+pub fn tcp_listen() -> IoResult<(String, Receiver<Message>)> {
     let listener = get_listener()?;
     let addr = listener.local_addr();
 
@@ -33,7 +45,7 @@ pub fn tcp_listen() -> IoResult<(String, Receiver<(String, Vec<EResult>)>)> {
             Ok((conn, _)) => {
                 spawn(move || match serde_json::from_reader(conn) {
                     Ok(out) => {
-                        let _ = sender.send(out);
+                        sender.send(out);
                     }
                     Err(e) => eprintln!("{}", e),
                 });
@@ -52,7 +64,7 @@ pub fn process_listen(mut command: Command) -> IoResult<Receiver<()>> {
     let mut handle = command.spawn()?;
     spawn(move || {
         let _ = handle.wait();
-        let _ = sender.send(());
+        sender.send(());
     });
 
     Ok(receiver)
@@ -72,6 +84,16 @@ fn prepare_command(spec: Specifier, send_ser: String) -> Command {
     if !spec.filetypes.is_empty() {
         command.env("CARGO_EXPECT_FILES", spec.filetypes.join(","));
     }
+    if spec.index {
+        command.env("CARGO_EXPECT_INDEX", "1");
+    }
+    if spec.ci {
+        command.env("EXPECTATION_CI", "1");
+    }
+    // The tests would promote their own files if this were inherited.
+    if spec.dry_run {
+        command.env_remove("EXPECTATION_UPDATE");
+    }
     command.env("CARGO_EXPECT_IPC", send_ser);
     command.stdout(Stdio::null());
     command.stderr(Stdio::null());
@@ -90,36 +112,43 @@ fn run_build(release: bool) -> IoResult<ExitStatus> {
     Ok(result)
 }
 
-pub fn perform_promote(spec: Specifier) -> IoResult<bool> {
+pub fn perform_promote(spec: Specifier, config: &Config) -> IoResult<bool> {
+    if spec.ci && !spec.dry_run {
+        eprintln!("{} Snapshots can't be promoted in CI; promote them locally and commit them", "✘".red());
+        return Ok(false);
+    }
+    if spec.from_last_run {
+        return perform_promote_last_run(spec, config);
+    }
     if !run_build(spec.release)?.success() {
         return Ok(false);
     }
+    if spec.dry_run {
+        return perform_dry_run(spec);
+    }
     println!("Promoting Library");
 
     let verbose = spec.verbose;
+    let allow_errors = spec.allow_errors;
     let (send_ser, messages) = tcp_listen().unwrap();
     let command = prepare_command(spec, send_ser);
     let done_recvr = process_listen(command);
 
     let fs = RealFileSystem { root: "/".into() };
-    let mut success = true;
-    let mut files_promoted_count = 0;
+    let mut summary = PromotionSummary::default();
+    let mut roots = BTreeSet::new();
+    let mut history = History::new();
+    let mut rerun = BTreeSet::new();
 
     'a: loop {
         select![
             recv(messages, item) => {
                 match item {
-                    Some((name, results)) => {
-                        let rs: Vec<_> =
-                            results.into_iter()
-                                   .map(|r| {
-                                       let p = promote(&r.kind, fs.duplicate());
-                                       (r, p)
-                                   })
-                                   .collect();
-                        let (s, c_count) =  crate::output::print_promotion(&name, rs, verbose);
-                        success &= s;
-                        files_promoted_count += c_count;
+                    Some((name, root, results)) => {
+                        rerun.insert((name.clone(), root.clone()));
+                        roots.insert(root);
+                        let rs = promote_results(results, &fs, &mut history);
+                        summary.add(crate::output::print_promotion(&name, rs, verbose));
                     },
                     None => { break 'a; }
                 }
@@ -128,34 +157,308 @@ pub fn perform_promote(spec: Specifier) -> IoResult<bool> {
         ]
     }
 
-    loop {
-        if let Some((name, results)) = messages.try_recv() {
-            let rs: Vec<_> = results
-                .into_iter()
-                .map(|r| {
-                    let p = promote(&r.kind, fs.duplicate());
-                    (r, p)
-                }).collect();
-            let (s, c_count) = crate::output::print_promotion(&name, rs, verbose);
-            success &= s;
-            files_promoted_count += c_count;
-        } else {
-            break;
+    while let Some((name, root, results)) = messages.try_recv() {
+        rerun.insert((name.clone(), root.clone()));
+        roots.insert(root);
+        let rs = promote_results(results, &fs, &mut history);
+        summary.add(crate::output::print_promotion(&name, rs, verbose));
+    }
+
+    forget_last_run_of(&fs, &last_run_path(config)?, &rerun)?;
+    Ok(print_promote_summary(&summary, allow_errors, &roots, &history))
+}
+
+fn print_promote_summary(
+    summary: &PromotionSummary,
+    allow_errors: bool,
+    roots: &BTreeSet<PathBuf>,
+    history: &History,
+) -> bool {
+    let success = crate::output::print_promotion_summary(summary, allow_errors);
+    for root in roots {
+        println!("  ► Snapshots in {}", root.to_string_lossy());
+    }
+    for (root, batch) in history.recorded() {
+        println!(
+            "  ► Recorded as batch {} in {}; `cargo expect undo` reverts it",
+            batch,
+            root.to_string_lossy()
+        );
+    }
+    success
+}
+
+/// Promotes the results saved by the last `cargo expect run`.  The results
+/// that the filters leave out stay recorded, so that they can be promoted
+/// later.
+fn perform_promote_last_run(spec: Specifier, config: &Config) -> IoResult<bool> {
+    let fs = RealFileSystem { root: "/".into() };
+    let path = last_run_path(config)?;
+    let recorded = load_last_run(&fs, &path).and_then(|(messages, digests)| {
+        let (selected, rest) = select_last_run(messages, spec.filter.as_deref(), &spec.filetypes, config);
+        check_last_run(&fs, &selected, &digests)?;
+        Ok((selected, rest, digests))
+    });
+    let (selected, rest, digests) = match recorded {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            eprintln!("{} No results from a previous `cargo expect run` to promote: {}", "✘".red(), e);
+            return Ok(false);
+        }
+    };
+
+    if spec.dry_run {
+        println!("Previewing Promotion of the Last Run");
+        let planned: usize = selected.iter().map(|(name, _, results)| crate::preview::print_plan(name, results)).sum();
+        println!("{} Files would be promoted (dry run, nothing was changed)", planned);
+        return Ok(true);
+    }
+    println!("Promoting the Last Run");
+    let allow_errors = spec.allow_errors;
+
+    let mut summary = PromotionSummary::default();
+    let mut roots = BTreeSet::new();
+    let mut history = History::new();
+
+    for (name, root, results) in selected {
+        roots.insert(root);
+        let rs = promote_results(results, &fs, &mut history);
+        summary.add(crate::output::print_promotion(&name, rs, spec.verbose));
+    }
+
+    save_last_run(&fs, &path, &rest, &digests)?;
+    Ok(print_promote_summary(&summary, allow_errors, &roots, &history))
+}
+
+/// Splits the recorded results into those of the tests matching `filter`
+/// with files of the `filetypes`, which are to be promoted, and the rest.
+fn select_last_run(
+    messages: Vec<Message>,
+    filter: Option<&str>,
+    filetypes: &[String],
+    config: &Config,
+) -> (Vec<Message>, Vec<Message>) {
+    let mut selected = vec![];
+    let mut rest = vec![];
+    for (name, root, results) in messages {
+        let in_filter = filter.map(|f| name.contains(f)).unwrap_or(true);
+        let (chosen, left): (Vec<_>, Vec<_>) = results
+            .into_iter()
+            .partition(|r| in_filter && config.accepts(&r.file_name, Some(filetypes)));
+        if !chosen.is_empty() {
+            selected.push((name.clone(), root.clone(), chosen));
+        }
+        if !left.is_empty() {
+            rest.push((name, root, left));
+        }
+    }
+    (selected, rest)
+}
+
+fn last_run_path(config: &Config) -> IoResult<PathBuf> {
+    Ok(config.snapshot_root(&::std::env::current_dir()?).join(LAST_RUN_FILE))
+}
+
+/// The digest of each actual file that recorded results refer to, or `None`
+/// where it was missing, to tell whether the results are still up to date.
+type Digests = BTreeMap<PathBuf, Option<String>>;
+
+/// The actual file that promoting `result` would read, or whose absence it
+/// relies on.
+fn actual_path(result: &EResult) -> Option<&Path> {
+    match &result.kind {
+        ResultKind::ExpectedNotFound(double) | ResultKind::ActualNotFound(double) => Some(&double.actual),
+        ResultKind::Difference(tripple) => Some(&tripple.actual),
+        _ => None,
+    }
+}
+
+/// The current digests of the actual files of `results`.
+fn digests(fs: &dyn FileSystem, results: &[Message]) -> Digests {
+    results
+        .iter()
+        .flat_map(|(_, _, results)| results.iter().filter_map(actual_path))
+        .map(|path| (path.to_owned(), index::digest(fs, path).ok()))
+        .collect()
+}
+
+/// Records the results of a run so that `promote --from-last-run` can use
+/// them, along with the digests of their actual files at the time.
+/// Recording no results removes the record.
+fn save_last_run(fs: &dyn FileSystem, path: &Path, results: &[Message], digests: &Digests) -> IoResult<()> {
+    if results.is_empty() {
+        return match fs.remove(path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            other => other,
+        };
+    }
+    let digests: Digests = results
+        .iter()
+        .flat_map(|(_, _, results)| results.iter().filter_map(actual_path))
+        .filter_map(|path| digests.get(path).map(|d| (path.to_owned(), d.clone())))
+        .collect();
+    fs.write(path, &mut |w| serde_json::to_writer(w, &(results, &digests)).map_err(IoError::other))
+}
+
+fn load_last_run(fs: &dyn FileSystem, path: &Path) -> IoResult<(Vec<Message>, Digests)> {
+    let mut recorded = None;
+    fs.read(path, &mut |r| {
+        recorded = Some(serde_json::from_reader(r).map_err(IoError::other)?);
+        Ok(())
+    })?;
+    Ok(recorded.expect("read calls back on success"))
+}
+
+/// Fails if an actual file of the recorded results has changed since they
+/// were recorded, like when `cargo test` ran the tests again, since the
+/// results no longer describe what would be promoted.
+fn check_last_run(fs: &dyn FileSystem, results: &[Message], recorded: &Digests) -> IoResult<()> {
+    let current = digests(fs, results);
+    for (path, digest) in &current {
+        if recorded.get(path) != Some(digest) {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} changed since it was recorded; run `cargo expect run` again",
+                    path.to_string_lossy()
+                ),
+            ));
         }
     }
+    Ok(())
+}
+
+/// Removes the recorded results of the tests that were run again, which are
+/// out of date now, and keeps those of the other tests.
+fn forget_last_run_of(fs: &dyn FileSystem, path: &Path, tests: &BTreeSet<(String, PathBuf)>) -> IoResult<()> {
+    let (recorded, digests) = match load_last_run(fs, path) {
+        Ok(recorded) => recorded,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let rest: Vec<_> = recorded
+        .into_iter()
+        .filter(|(name, root, _)| !tests.contains(&(name.clone(), root.clone())))
+        .collect();
+    save_last_run(fs, path, &rest, &digests)
+}
 
-    println!("{} Files Promoted", files_promoted_count);
+fn perform_dry_run(spec: Specifier) -> IoResult<bool> {
+    println!("Previewing Promotion");
+
+    let (send_ser, messages) = tcp_listen().unwrap();
+    let command = prepare_command(spec, send_ser);
+    let done_recvr = process_listen(command);
+
+    let mut planned = 0;
+
+    'a: loop {
+        select![
+            recv(messages, item) => {
+                match item {
+                    Some((name, _, results)) => {
+                        planned += crate::preview::print_plan(&name, &results);
+                    },
+                    None => { break 'a; }
+                }
+            },
+            recv(done_recvr, _) => { break 'a; }
+        ]
+    }
 
+    while let Some((name, _, results)) = messages.try_recv() {
+        planned += crate::preview::print_plan(&name, &results);
+    }
+
+    println!("{} Files would be promoted (dry run, nothing was changed)", planned);
+    Ok(true)
+}
+
+/// The latest history batch of a snapshot root.
+#[derive(Clone)]
+struct LatestBatch {
+    root: PathBuf,
+    batch: u32,
+    run: Option<String>,
+    /// When it last recorded a file, in seconds since the unix epoch.
+    recorded: u64,
+}
+
+fn latest_batches(roots: &[PathBuf]) -> IoResult<Vec<LatestBatch>> {
+    let mut latest = vec![];
+    for root in roots {
+        let fs = RealFileSystem { root: root.clone() };
+        if let Some(&batch) = history::batches(&fs).last() {
+            let recorded = history::entries(&fs, batch)?.iter().map(|e| e.timestamp).max();
+            latest.push(LatestBatch {
+                root: root.clone(),
+                batch,
+                run: history::run_of(&fs, batch),
+                recorded: recorded.unwrap_or(0),
+            });
+        }
+    }
+    Ok(latest)
+}
+
+/// Reverts the latest promotion, in every snapshot root that it recorded a
+/// batch in.  With `batch`, only does so if that is the batch it recorded.
+pub fn perform_undo(roots: &[PathBuf], batch: Option<u32>) -> IoResult<bool> {
+    let latest = latest_batches(roots)?;
+    let newest = match latest.iter().max_by_key(|l| l.recorded) {
+        Some(newest) => newest.clone(),
+        None => {
+            println!("{} There are no promotions to undo", "✘".red());
+            return Ok(false);
+        }
+    };
+    let targets: Vec<_> = latest
+        .into_iter()
+        .filter(|l| l.root == newest.root || (l.run.is_some() && l.run == newest.run))
+        .collect();
+    if let Some(batch) = batch
+        && let Some(other) = targets.iter().find(|l| l.batch != batch)
+    {
+        println!(
+            "{} The latest promotion in {} is batch {}, not batch {}; it has to be undone first",
+            "✘".red(),
+            other.root.to_string_lossy(),
+            other.batch,
+            batch
+        );
+        return Ok(false);
+    }
+
+    let mut success = true;
+    for LatestBatch { root, batch, .. } in targets {
+        match history::undo(&RealFileSystem { root: root.clone() }, Some(batch)) {
+            Ok((batch, entries)) => {
+                println!("{} Undid batch {} in {}", "✔".green(), batch, root.to_string_lossy());
+                for entry in entries {
+                    let undone = match entry.action {
+                        Action::Created => "removed",
+                        Action::Replaced | Action::Removed => "restored",
+                    };
+                    println!("  ► {} {}", undone, entry.file.to_string_lossy());
+                }
+            }
+            Err(e) => {
+                success = false;
+                println!("{} {}: {}", "✘".red(), root.to_string_lossy(), e);
+            }
+        }
+    }
     Ok(success)
 }
 
-pub fn perform_run(spec: Specifier) -> IoResult<bool> {
+pub fn perform_run(spec: Specifier, config: &Config) -> IoResult<bool> {
     if !run_build(spec.release)?.success() {
         return Ok(false);
     }
     println!("Running Library");
 
     let verbose = spec.verbose;
+    let ci = spec.ci;
     let (send_ser, messages) = tcp_listen().unwrap();
     let command = prepare_command(spec, send_ser);
     let done_recvr = process_listen(command);
@@ -166,9 +469,9 @@ pub fn perform_run(spec: Specifier) -> IoResult<bool> {
         select![
             recv(messages, item) => {
                 match item {
-                    Some((name, results)) => {
+                    Some((name, root, results)) => {
                         crate::output::print_results(&name, &results, verbose);
-                        total_results.push((name, results));
+                        total_results.push((name, root, results));
                     },
                     None => { break 'a; }
                 }
@@ -177,25 +480,28 @@ pub fn perform_run(spec: Specifier) -> IoResult<bool> {
         ]
     }
 
-    loop {
-        if let Some((name, results)) = messages.try_recv() {
-            crate::output::print_results(&name, &results, verbose);
-            total_results.push((name, results));
-        } else {
-            break;
-        }
+    while let Some((name, root, results)) = messages.try_recv() {
+        crate::output::print_results(&name, &results, verbose);
+        total_results.push((name, root, results));
     }
 
     let mut total_suites = 0;
     let mut failed_suites = 0;
     let mut total_files = 0;
     let mut failed_files = 0;
+    let mut unchanged_files = 0;
 
-    for (_, results) in total_results {
+    let mut roots = BTreeSet::new();
+
+    for (_, root, results) in &total_results {
+        roots.insert(root);
         total_suites += 1;
         let mut success = true;
         for file in results {
             total_files += 1;
+            if file.unchanged {
+                unchanged_files += 1;
+            }
             if !file.is_ok() {
                 failed_files += 1;
                 success = false;
@@ -227,6 +533,181 @@ pub fn perform_run(spec: Specifier) -> IoResult<bool> {
         total_files - failed_files,
         total_files
     );
+    if unchanged_files > 0 {
+        println!(
+            "  {} {} files unchanged since last run",
+            colorizer("►"),
+            unchanged_files
+        );
+    }
+
+    for root in roots {
+        println!("  {} Snapshots: {}", colorizer("►"), root.to_string_lossy());
+    }
+
+    if ci {
+        crate::output::print_bless_summary(&total_results);
+    }
+
+    // In CI the actual files are in a temporary directory and the snapshot
+    // directory is left alone, so there's nothing to promote later.
+    if !ci {
+        let fs = RealFileSystem { root: "/".into() };
+        let digests = digests(&fs, &total_results);
+        save_last_run(&fs, &last_run_path(config)?, &total_results, &digests)?;
+    }
+
+    if let Some(path) = &config.report.json {
+        write_json_report(path, &total_results)?;
+        println!("  {} Report: {}", colorizer("►"), path.to_string_lossy());
+    }
 
     Ok(failed_suites == 0)
 }
+
+fn write_json_report(path: &::std::path::Path, results: &[Message]) -> IoResult<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(file, results).map_err(IoError::other)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::workspace::test::TempDir;
+    use std::fs::{read_to_string, write};
+
+    fn promote_file(history: &mut History, root: &Path, contents: &str) {
+        let actual = root.join("actual/hi/foo.txt");
+        create_dir_all(actual.parent().unwrap()).unwrap();
+        write(&actual, contents).unwrap();
+        let result = EResult::difference("hi", "foo.txt", actual, root.join("expected/hi/foo.txt"), vec![]);
+        let fs = RealFileSystem { root: "/".into() };
+        assert!(promote_results(vec![result], &fs, history).iter().all(|(_, p)| p.is_ok()));
+    }
+
+    fn write_fake(fs: &FakeFileSystem, path: &str, contents: &str) {
+        fs.write(Path::new(path), &mut |w| w.write_all(contents.as_bytes())).unwrap();
+    }
+
+    /// Two tests' results whose actual files are in a fake filesystem.
+    fn recorded_run(fs: &FakeFileSystem) -> Vec<Message> {
+        write_fake(fs, "/root/actual/foo/a.txt", "a");
+        write_fake(fs, "/root/actual/foo/b.svg", "b");
+        write_fake(fs, "/root/actual/bar/c.txt", "c");
+        let result = |test: &str, file: &str| {
+            EResult::expected_not_found(
+                test,
+                file,
+                format!("/root/actual/{}/{}", test, file),
+                format!("/root/expected/{}/{}", test, file),
+            )
+        };
+        vec![
+            ("foo".into(), "/root".into(), vec![result("foo", "a.txt"), result("foo", "b.svg")]),
+            ("bar".into(), "/root".into(), vec![result("bar", "c.txt")]),
+        ]
+    }
+
+    #[test]
+    fn last_run_round_trips() {
+        let fs = FakeFileSystem::new();
+        let path = Path::new("/root/.last-run");
+        let run = recorded_run(&fs);
+        let mut digests = digests(&fs, &run);
+        let unrelated = PathBuf::from("/root/actual/gone/d.txt");
+        digests.insert(unrelated.clone(), None);
+
+        save_last_run(&fs, path, &run, &digests).unwrap();
+        let (loaded, loaded_digests) = load_last_run(&fs, path).unwrap();
+        assert_eq!(loaded, run);
+        assert_eq!(loaded_digests.len(), 3);
+        assert!(!loaded_digests.contains_key(&unrelated));
+        check_last_run(&fs, &loaded, &loaded_digests).unwrap();
+
+        // Saving no results removes the record.
+        save_last_run(&fs, path, &[], &digests).unwrap();
+        assert!(!fs.exists(path));
+    }
+
+    #[test]
+    fn a_missing_last_run_is_not_found() {
+        let fs = FakeFileSystem::new();
+        let path = Path::new("/root/.last-run");
+        assert_eq!(load_last_run(&fs, path).unwrap_err().kind(), ErrorKind::NotFound);
+        forget_last_run_of(&fs, path, &BTreeSet::new()).unwrap();
+        assert!(!fs.exists(path));
+    }
+
+    #[test]
+    fn only_the_promoted_results_must_be_unchanged() {
+        let fs = FakeFileSystem::new();
+        let config = Config::default();
+        let run = recorded_run(&fs);
+        let digests = digests(&fs, &run);
+        write_fake(&fs, "/root/actual/bar/c.txt", "changed by another run");
+
+        let err = check_last_run(&fs, &run, &digests).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("/root/actual/bar/c.txt"), "{}", err);
+
+        let (selected, _) = select_last_run(run, Some("foo"), &[], &config);
+        check_last_run(&fs, &selected, &digests).unwrap();
+    }
+
+    #[test]
+    fn last_run_selection_follows_the_filters() {
+        let fs = FakeFileSystem::new();
+        let config = Config::default();
+        let names = |messages: &[Message]| {
+            messages
+                .iter()
+                .flat_map(|(_, _, results)| results.iter().map(|r| r.file_name.to_string_lossy().into_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        let (selected, rest) = select_last_run(recorded_run(&fs), None, &[], &config);
+        assert_eq!(names(&selected), vec!["a.txt", "b.svg", "c.txt"]);
+        assert!(rest.is_empty());
+
+        let (selected, rest) = select_last_run(recorded_run(&fs), Some("foo"), &[], &config);
+        assert_eq!(names(&selected), vec!["a.txt", "b.svg"]);
+        assert_eq!(names(&rest), vec!["c.txt"]);
+
+        let (selected, rest) = select_last_run(recorded_run(&fs), Some("foo"), &["svg".into()], &config);
+        assert_eq!(names(&selected), vec!["b.svg"]);
+        assert_eq!(names(&rest), vec!["a.txt", "c.txt"]);
+        assert_eq!(rest.iter().map(|(name, _, _)| name.as_str()).collect::<Vec<_>>(), vec!["foo", "bar"]);
+    }
+
+    #[test]
+    fn undo_reverts_the_latest_promotion_in_every_root() {
+        let temp = TempDir::new("undo");
+        let roots = vec![temp.0.join("a"), temp.0.join("b")];
+        let expected = |root: &Path| read_to_string(root.join("expected/hi/foo.txt")).unwrap();
+        for root in &roots {
+            create_dir_all(root.join("expected/hi")).unwrap();
+            write(root.join("expected/hi/foo.txt"), "one").unwrap();
+        }
+
+        let mut history = History::new();
+        for root in &roots {
+            promote_file(&mut history, root, "two");
+        }
+        assert!(perform_undo(&roots, None).unwrap());
+        assert_eq!(expected(&roots[0]), "one");
+        assert_eq!(expected(&roots[1]), "one");
+
+        promote_file(&mut History::new(), &roots[0], "two");
+        promote_file(&mut History::new(), &roots[0], "three");
+        assert!(!perform_undo(&roots, Some(1)).unwrap());
+        assert_eq!(expected(&roots[0]), "three");
+        assert!(perform_undo(&roots, Some(2)).unwrap());
+        assert_eq!(expected(&roots[0]), "two");
+        assert!(perform_undo(&roots, None).unwrap());
+        assert!(!perform_undo(&roots, None).unwrap());
+        assert_eq!(expected(&roots[0]), "one");
+    }
+}
@@ -0,0 +1,270 @@
+use colored::*;
+use std::collections::BTreeMap;
+use std::fs::remove_file;
+use std::io::{Error as IoError, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How an expected file differs from `HEAD`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// A new file that git doesn't know about yet.
+    Untracked,
+    /// A new file that has been staged.
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl Change {
+    fn describe(self) -> ColoredString {
+        match self {
+            Change::Untracked => "untracked".green(),
+            Change::Added => "added".green(),
+            Change::Modified => "modified".yellow(),
+            Change::Deleted => "deleted".red(),
+        }
+    }
+}
+
+/// A changed file in the `expected` directory.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub test: String,
+    /// The path of the file relative to the test's directory.
+    pub file: PathBuf,
+    /// The path of the file relative to the root of the repository.
+    pub path: PathBuf,
+    /// The root of the repository.
+    pub toplevel: PathBuf,
+    pub change: Change,
+}
+
+fn git(dir: &Path, args: &[&str]) -> IoResult<Vec<u8>> {
+    let output = Command::new("git").current_dir(dir).args(args).output()?;
+    if !output.status.success() {
+        return Err(IoError::other(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Parses the output of `git status --porcelain -z`.  Only files that are in
+/// `HEAD` are reported as modified or deleted, so that restoring them from
+/// `HEAD` works.
+fn parse_status(output: &[u8]) -> Vec<(Change, PathBuf)> {
+    let to_path = |bytes: &[u8]| PathBuf::from(String::from_utf8_lossy(bytes).into_owned());
+    let mut out = vec![];
+    let mut records = output.split(|&b| b == 0).filter(|r| !r.is_empty());
+    while let Some(record) = records.next() {
+        if record.len() < 4 {
+            continue;
+        }
+        let (x, y) = (record[0], record[1]);
+        let path = to_path(&record[3..]);
+        // Renames and copies are followed by the path they came from.  A
+        // renamed file is gone from where it was in `HEAD`.
+        if x == b'R' || x == b'C' {
+            let source = records.next();
+            if let (b'R', Some(source)) = (x, source) {
+                out.push((Change::Deleted, to_path(source)));
+            }
+        }
+        let change = match (x, y) {
+            (b'?', b'?') => Change::Untracked,
+            (b'A', _) | (b'R', _) | (b'C', _) => Change::Added,
+            (b'D', _) | (_, b'D') => Change::Deleted,
+            _ => Change::Modified,
+        };
+        out.push((change, path));
+    }
+    out
+}
+
+/// Finds the expected files in the snapshot `roots` that differ from `HEAD`,
+/// for tests whose name contains `filter`.
+pub fn changed_snapshots(roots: &[PathBuf], filter: Option<&str>) -> IoResult<Vec<Snapshot>> {
+    let mut snapshots = vec![];
+    for root in roots {
+        let expected = root.join("expected");
+        if expected.exists() {
+            changed_in(&expected, filter, &mut snapshots)?;
+        }
+    }
+    Ok(snapshots)
+}
+
+fn changed_in(expected: &Path, filter: Option<&str>, snapshots: &mut Vec<Snapshot>) -> IoResult<()> {
+    let expected = expected.canonicalize()?;
+    let toplevel = git(&expected, &["rev-parse", "--show-toplevel"])?;
+    let toplevel = PathBuf::from(String::from_utf8_lossy(&toplevel).trim()).canonicalize()?;
+    let status = git(
+        &expected,
+        &["status", "--porcelain", "-z", "--untracked-files=all", "--", "."],
+    )?;
+
+    for (change, path) in parse_status(&status) {
+        let relative = match toplevel.join(&path).strip_prefix(&expected) {
+            Ok(relative) => relative.to_owned(),
+            Err(_) => continue,
+        };
+        let mut components = relative.components();
+        let test = match components.next() {
+            Some(test) => test.as_os_str().to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if filter.is_some_and(|f| !test.contains(f)) {
+            continue;
+        }
+        snapshots.push(Snapshot {
+            test,
+            file: components.as_path().to_owned(),
+            path,
+            toplevel: toplevel.clone(),
+            change,
+        });
+    }
+    Ok(())
+}
+
+fn group(snapshots: &[Snapshot]) -> BTreeMap<&str, Vec<&Snapshot>> {
+    let mut groups: BTreeMap<&str, Vec<&Snapshot>> = BTreeMap::new();
+    for snapshot in snapshots {
+        groups.entry(&snapshot.test).or_default().push(snapshot);
+    }
+    groups
+}
+
+pub fn perform_status(roots: &[PathBuf], filter: Option<&str>) -> IoResult<bool> {
+    let snapshots = changed_snapshots(roots, filter)?;
+    if snapshots.is_empty() {
+        println!("{} No expected files differ from HEAD", "✔".green());
+        return Ok(true);
+    }
+
+    for (test, snapshots) in group(&snapshots) {
+        println!("{} {}", "◼".yellow(), test);
+        for snapshot in snapshots {
+            println!(
+                "  {} {} ({})",
+                "•".yellow(),
+                snapshot.file.to_string_lossy(),
+                snapshot.change.describe()
+            );
+        }
+    }
+    println!("{} expected files differ from HEAD", snapshots.len());
+    Ok(true)
+}
+
+/// Puts the expected files of the matching tests back the way they are in
+/// `HEAD`, undoing promotions that haven't been committed.
+pub fn perform_restore(roots: &[PathBuf], filter: Option<&str>) -> IoResult<bool> {
+    let snapshots = changed_snapshots(roots, filter)?;
+    let mut success = true;
+
+    for (test, snapshots) in group(&snapshots) {
+        println!("{} {}", "◼".yellow(), test);
+        for snapshot in snapshots {
+            let path = snapshot.path.to_string_lossy();
+            let toplevel = snapshot.toplevel.as_path();
+            let result = match snapshot.change {
+                Change::Untracked => remove_file(toplevel.join(&snapshot.path)),
+                Change::Added => git(toplevel, &["rm", "--quiet", "--force", "--", &path]).map(|_| ()),
+                Change::Modified | Change::Deleted => git(
+                    toplevel,
+                    &["restore", "--source=HEAD", "--staged", "--worktree", "--", &path],
+                ).map(|_| ()),
+            };
+            match result {
+                Ok(()) => println!("  {} {} restored", "✔".green(), snapshot.file.to_string_lossy()),
+                Err(e) => {
+                    success = false;
+                    println!("  {} {}: {}", "✘".red(), snapshot.file.to_string_lossy(), e);
+                }
+            }
+        }
+    }
+    if success && snapshots.is_empty() {
+        println!("{} Nothing to restore", "✔".green());
+    }
+    Ok(success)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::workspace::{snapshot_roots, test::workspace};
+
+    fn parse(output: &str) -> Vec<(Change, PathBuf)> {
+        parse_status(output.as_bytes())
+    }
+
+    #[test]
+    fn status_of_new_changed_and_deleted_files() {
+        assert_eq!(
+            parse("?? a.txt\0 M b.txt\0D  c.txt\0A  d.txt\0"),
+            vec![
+                (Change::Untracked, "a.txt".into()),
+                (Change::Modified, "b.txt".into()),
+                (Change::Deleted, "c.txt".into()),
+                (Change::Added, "d.txt".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn staged_files_that_were_deleted_again_are_not_in_head() {
+        assert_eq!(parse("AD a.txt\0"), vec![(Change::Added, "a.txt".into())]);
+    }
+
+    #[test]
+    fn renames_delete_the_file_they_came_from() {
+        assert_eq!(
+            parse("R  new.txt\0old.txt\0 M b.txt\0"),
+            vec![
+                (Change::Deleted, "old.txt".into()),
+                (Change::Added, "new.txt".into()),
+                (Change::Modified, "b.txt".into()),
+            ]
+        );
+        assert_eq!(
+            parse("C  copy.txt\0original.txt\0"),
+            vec![(Change::Added, "copy.txt".into())]
+        );
+    }
+
+    #[test]
+    fn snapshots_of_every_member_of_the_workspace() {
+        let temp = workspace("status");
+        let root = &temp.0;
+        git(root, &["init", "--quiet"]).unwrap();
+        for file in ["nested/snapshots/nested/expected/hi/foo.txt", "plain/expectation-tests/expected/other/bar.txt"] {
+            let path = root.join(file);
+            ::std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            ::std::fs::write(path, "new").unwrap();
+        }
+        let changed = |dir: &Path| {
+            changed_snapshots(&snapshot_roots(dir).unwrap(), None)
+                .unwrap()
+                .into_iter()
+                .map(|s| (s.test, s.file, s.path, s.change))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            changed(root),
+            vec![
+                ("hi".into(), "foo.txt".into(), "nested/snapshots/nested/expected/hi/foo.txt".into(), Change::Untracked),
+                ("other".into(), "bar.txt".into(), "plain/expectation-tests/expected/other/bar.txt".into(), Change::Untracked),
+            ]
+        );
+        assert_eq!(
+            changed(&root.join("nested")),
+            vec![("hi".into(), "foo.txt".into(), "nested/snapshots/nested/expected/hi/foo.txt".into(), Change::Untracked)]
+        );
+    }
+}
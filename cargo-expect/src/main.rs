@@ -5,12 +5,17 @@ extern crate serde_json;
 #[macro_use]
 extern crate crossbeam;
 extern crate colored;
+extern crate diff;
+extern crate image;
 
+use expectation_shared::config::Config;
 use std::io::Result as IoResult;
 use structopt::StructOpt;
 mod command;
+mod git;
 mod output;
-mod promote;
+mod preview;
+mod workspace;
 
 #[derive(StructOpt, Debug)]
 pub struct Specifier {
@@ -19,7 +24,8 @@ pub struct Specifier {
     filter: Option<String>,
 
     /// Filetypes is a filter for which kinds of files are considered
-    /// when running tests and promoting results.
+    /// when running tests and promoting results.  Defaults to the
+    /// `filetypes` in `expectation.toml`.
     #[structopt(short = "f", long = "filetypes")]
     filetypes: Vec<String>,
 
@@ -28,6 +34,60 @@ pub struct Specifier {
 
     #[structopt(long = "release")]
     release: bool,
+
+    /// Skips comparing files that haven't changed since they last matched,
+    /// using the digests stored in `expectation-tests/.index`.
+    #[structopt(long = "index")]
+    index: bool,
+
+    /// Never writes to the snapshot directory: actual files go to a
+    /// temporary directory per test, which is removed if the test passes,
+    /// and promoting is refused.  Also enabled by setting `EXPECTATION_CI=1`.
+    #[structopt(long = "ci")]
+    ci: bool,
+
+    /// Runs the tests and shows what promoting would change, without
+    /// changing anything.
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Promotes the results recorded by the last `cargo expect run`
+    /// instead of running the tests again.  Fails if the actual files have
+    /// changed since, like after a plain `cargo test`.
+    #[structopt(long = "from-last-run")]
+    from_last_run: bool,
+
+    /// Succeeds even if some files couldn't be promoted because the test
+    /// run reported errors for them.
+    #[structopt(long = "allow-errors")]
+    allow_errors: bool,
+}
+
+impl Specifier {
+    /// Fills in the options that weren't given on the command line.
+    fn apply_config(&mut self, config: &Config) {
+        if self.filetypes.is_empty() {
+            self.filetypes = config.filetypes.clone();
+        }
+        if let Ok(v) = ::std::env::var("EXPECTATION_CI") {
+            self.ci |= !v.is_empty() && v != "0";
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct TestFilter {
+    /// Only considers tests whose name contains this
+    #[structopt(name = "filter")]
+    filter: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct UndoSpec {
+    /// Only undoes the latest promotion if it was recorded as this batch.
+    /// Older batches can't be undone before the ones that came after them.
+    #[structopt(long = "batch")]
+    batch: Option<u32>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -42,6 +102,14 @@ pub struct Specifier {
     cargo expect promote -f svg               # promotes all tests but only promotes svg files produced by those tests
     cargo expect promote my_test_name         # promotes all files in tests that match "my_test_name"
     cargo expect promote my_test_name -f svg  # promotes only svg files for tests that match "my_test_name"
+    cargo expect promote --dry-run            # shows what promoting all tests would change
+    cargo expect promote --from-last-run      # promotes the results of the last `cargo expect run` without running the tests
+
+    cargo expect status                # lists expected files that differ from git HEAD, by test
+    cargo expect restore my_test_name  # reverts uncommitted changes to the expected files of "my_test_name"
+
+    cargo expect undo                  # reverts the latest promotion
+    cargo expect undo --batch 3        # reverts the latest promotion, but only if it is batch 3
 "#
 )]
 pub enum Command {
@@ -53,6 +121,18 @@ pub enum Command {
     #[structopt(name = "promote")]
     Promote(Specifier),
 
+    /// Lists the expected files that differ from git HEAD, grouped by test
+    #[structopt(name = "status")]
+    Status(TestFilter),
+
+    /// Reverts uncommitted changes to expected files, undoing promotions
+    #[structopt(name = "restore")]
+    Restore(TestFilter),
+
+    /// Reverts the latest promotion using the history in each snapshot directory's `.history`
+    #[structopt(name = "undo")]
+    Undo(UndoSpec),
+
     /// Cleans up the expectation-tests directory by removing the "diff" and "actual" folders.
     #[structopt(name = "clean")]
     Clean,
@@ -64,16 +144,40 @@ fn main() -> IoResult<()> {
         args.remove(1);
     }
 
+    let config = Config::load(&::std::env::current_dir()?)?;
     let c = Command::from_iter(args);
     match c {
-        Command::Promote(spec) => {
-            let good = command::perform_promote(spec)?;
+        Command::Promote(mut spec) => {
+            spec.apply_config(&config);
+            let good = command::perform_promote(spec, &config)?;
+            if !good {
+                ::std::process::exit(1);
+            }
+        }
+        Command::Run(mut spec) => {
+            spec.apply_config(&config);
+            let good = command::perform_run(spec, &config)?;
+            if !good {
+                ::std::process::exit(1);
+            }
+        }
+        Command::Status(filter) => {
+            let roots = workspace::snapshot_roots(&::std::env::current_dir()?)?;
+            let good = git::perform_status(&roots, filter.filter.as_deref())?;
+            if !good {
+                ::std::process::exit(1);
+            }
+        }
+        Command::Restore(filter) => {
+            let roots = workspace::snapshot_roots(&::std::env::current_dir()?)?;
+            let good = git::perform_restore(&roots, filter.filter.as_deref())?;
             if !good {
                 ::std::process::exit(1);
             }
         }
-        Command::Run(spec) => {
-            let good = command::perform_run(spec)?;
+        Command::Undo(spec) => {
+            let roots = workspace::snapshot_roots(&::std::env::current_dir()?)?;
+            let good = command::perform_undo(&roots, spec.batch)?;
             if !good {
                 ::std::process::exit(1);
             }
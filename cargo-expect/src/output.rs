@@ -1,50 +1,131 @@
 use colored::*;
 use expectation_shared::{Result as EResult, ResultKind};
 use std::io::Result as IoResult;
+use std::path::PathBuf;
 
-pub fn print_promotion(name: &str, results: Vec<(EResult, IoResult<String>)>, verbose: bool) -> (bool, usize) {
-    let passed = results
-        .iter()
-        .all(|(_, r)|  r.is_ok());
+/// What promoting some results did, counted by file.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PromotionSummary {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    /// Files whose promotion was attempted but failed.
+    pub failed: usize,
+    /// Files that weren't promoted because the test run reported an error
+    /// for them, so there was nothing trustworthy to promote.
+    pub skipped: usize,
+}
+
+impl PromotionSummary {
+    pub fn add(&mut self, other: PromotionSummary) {
+        self.created += other.created;
+        self.updated += other.updated;
+        self.deleted += other.deleted;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+    }
+
+    pub fn promoted(&self) -> usize {
+        self.created + self.updated + self.deleted
+    }
+
+    fn describe(&self) -> String {
+        let mut parts = vec![
+            format!("{} created", self.created),
+            format!("{} updated", self.updated),
+            format!("{} deleted", self.deleted),
+        ];
+        if self.failed > 0 {
+            parts.push(format!("{} failed", self.failed));
+        }
+        if self.skipped > 0 {
+            parts.push(format!("{} skipped due to errors", self.skipped));
+        }
+        parts.join(", ")
+    }
+}
+
+pub fn print_promotion(name: &str, results: Vec<(EResult, IoResult<String>)>, verbose: bool) -> PromotionSummary {
+    let mut summary = PromotionSummary::default();
     let nothing_done = results
         .iter()
-        .all(|(r, _)|  match r.kind {
-            ResultKind::Ok => true,
-            _ => false,
-        });
-    let change_count = results
-        .iter()
-        .filter(|(r, _)| match r.kind {
-            ResultKind::Ok => false,
-            ResultKind::IoError(_) => false,
-            _ => true,
-        })
-        .count();
+        .all(|(r, _)| matches!(r.kind, ResultKind::Ok));
     if nothing_done {
-        return (passed, change_count);
+        return summary;
     }
 
+    let passed = results.iter().all(|(r, p)| {
+        p.is_ok()
+            && !matches!(
+                r.kind,
+                ResultKind::IoError(_) | ResultKind::DuplicateSnapshot(_) | ResultKind::UnexpectedFile(_)
+            )
+    });
     if passed {
         println!("︎{} {}", "✔".green(), name);
     } else {
         println!("{} {}", "✘".red(), name);
     }
 
-    for (EResult{file_name, ..}, io_result) in results {
-        match io_result {
-            Ok(detail) => {
+    for (EResult{file_name, kind, ..}, io_result) in results {
+        match (kind, io_result) {
+            (ResultKind::Ok, _) => {}
+            (ResultKind::IoError(e), _) => {
+                summary.skipped += 1;
                 println!(
-                    "  {} {}",
-                    "✔".green(),
+                    "  {} {} ❯ Skipped, the test run failed to produce it",
+                    "✘".red(),
+                    file_name.to_string_lossy()
+                );
+                println!("    ► {}", e);
+            }
+            (ResultKind::DuplicateSnapshot(dup), _) => {
+                summary.skipped += 1;
+                println!(
+                    "  {} {} ❯ Skipped, written twice by the test",
+                    "✘".red(),
+                    file_name.to_string_lossy()
+                );
+                println!("    ► Written at {} and {}", dup.first, dup.second);
+            }
+            (ResultKind::UnexpectedFile(actual), _) => {
+                summary.skipped += 1;
+                println!(
+                    "  {} {} ❯ Skipped, not requested from the provider",
+                    "✘".red(),
                     file_name.to_string_lossy()
                 );
+                println!("    ► {}", actual.to_string_lossy());
+            }
+            (kind, Ok(detail)) => {
+                let done = match kind {
+                    ResultKind::ExpectedNotFound(_) => {
+                        summary.created += 1;
+                        "created"
+                    }
+                    ResultKind::ActualNotFound(_) => {
+                        summary.deleted += 1;
+                        "deleted"
+                    }
+                    _ => {
+                        summary.updated += 1;
+                        "updated"
+                    }
+                };
+                println!(
+                    "  {} {} ❯ {}",
+                    "✔".green(),
+                    file_name.to_string_lossy(),
+                    done
+                );
                 if verbose {
                    println!("    ► {}", detail);
                 }
             }
-            Err(ioe) => {
+            (_, Err(ioe)) => {
+                summary.failed += 1;
                 println!(
-                    "  {} {} ❯ Error occurred during ",
+                    "  {} {} ❯ Error occurred during promotion",
                     "✘".red(),
                     file_name.to_string_lossy()
                 );
@@ -52,15 +133,29 @@ pub fn print_promotion(name: &str, results: Vec<(EResult, IoResult<String>)>, ve
             }
         }
     }
+    println!("  ► {}", summary.describe());
 
-    (passed, change_count)
+    summary
+}
+
+/// Prints the totals of a promotion, returning whether it succeeded: no
+/// file failed to promote, and no file was skipped unless `allow_errors`.
+pub fn print_promotion_summary(summary: &PromotionSummary, allow_errors: bool) -> bool {
+    let success = summary.failed == 0 && (summary.skipped == 0 || allow_errors);
+    let colorizer = |s: &str| if success { s.green() } else { s.red() };
+    println!("{} {} Files Promoted", colorizer("◼"), summary.promoted());
+    println!("  {} {}", colorizer("►"), summary.describe());
+    if summary.skipped > 0 && !allow_errors {
+        println!(
+            "  {} Files were skipped because of errors in the test run; fix the tests or pass --allow-errors",
+            colorizer("►")
+        );
+    }
+    success
 }
 
 pub fn print_results(name: &str, results: &[EResult], verbose: bool) {
-    let passed = results.iter().all(|r| match r.kind {
-        ResultKind::Ok => true,
-        _ => false,
-    });
+    let passed = results.iter().all(|r| r.is_ok());
     if passed {
         println!("︎{} {}", "✔".green(), name);
     } else {
@@ -76,12 +171,14 @@ pub fn print_results(name: &str, results: &[EResult], verbose: bool) {
             EResult {
                 file_name,
                 kind: ResultKind::Ok,
+                unchanged,
                 ..
             } => {
                 println!(
-                    "  {}︎ {} ❯ Ok",
+                    "  {}︎ {} ❯ Ok{}",
                     "✔".green(),
-                    file_name.to_string_lossy()
+                    file_name.to_string_lossy(),
+                    if *unchanged { " (unchanged)" } else { "" }
                 );
             }
             EResult {
@@ -155,6 +252,119 @@ pub fn print_results(name: &str, results: &[EResult], verbose: bool) {
                     error
                 );
             }
+            EResult {
+                file_name,
+                kind: ResultKind::DuplicateSnapshot(duplicate),
+                ..
+            } => {
+                println!(
+                    "  {} {} ❯ Written more than once",
+                    "✘".red(),
+                    file_name.to_string_lossy()
+                );
+                println!("    ► First:  {}", duplicate.first);
+                println!("    ► Second: {}", duplicate.second);
+            }
+            EResult {
+                file_name,
+                kind: ResultKind::UnexpectedFile(actual),
+                ..
+            } => {
+                println!(
+                    "  {} {} ❯ Unexpected File",
+                    "✘".red(),
+                    file_name.to_string_lossy()
+                );
+                println!("    ► Actual: {}", actual.to_string_lossy());
+                println!("    ► Not requested from the provider or in a directory snapshot");
+            }
+        }
+        if let Some(location) = &result.location
+            && !matches!(result.kind, ResultKind::Ok | ResultKind::DuplicateSnapshot(_))
+        {
+            println!("    ► Written at: {}", location);
         }
     }
 }
+
+/// Lists what a developer would have to promote locally to make a CI run
+/// pass.
+pub fn print_bless_summary(results: &[(String, PathBuf, Vec<EResult>)]) {
+    let mut tests = vec![];
+    for (name, _, results) in results {
+        let files: Vec<_> = results
+            .iter()
+            .filter_map(|r| {
+                let what = match r.kind {
+                    ResultKind::ExpectedNotFound(_) => "new",
+                    ResultKind::Difference(_) => "changed",
+                    ResultKind::ActualNotFound(_) => "removed",
+                    _ => return None,
+                };
+                Some((what, r.file_name.to_string_lossy()))
+            }).collect();
+        if !files.is_empty() {
+            tests.push((name, files));
+        }
+    }
+    if tests.is_empty() {
+        return;
+    }
+
+    println!("{} Snapshots to bless locally", "◼".yellow());
+    for (name, files) in &tests {
+        println!("  {} {}", "☛".yellow(), name);
+        for (what, file) in files {
+            println!("    • {} ({})", file, what);
+        }
+    }
+    let names: Vec<_> = tests.iter().map(|(name, _)| name.as_str()).collect();
+    println!("  Run `cargo expect promote <test>` for each of: {}", names.join(", "));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use expectation_shared::Location;
+    use std::io::Error as IoError;
+
+    fn location(line: u32) -> Location {
+        Location { file: "src/lib.rs".into(), line, column: 5 }
+    }
+
+    #[test]
+    fn promotions_are_counted_by_what_happened_to_each_file() {
+        let results = vec![
+            (EResult::ok("hi", "ok.txt"), Ok(String::new())),
+            (EResult::expected_not_found("hi", "new.txt", "/a/new.txt", "/e/new.txt"), Ok(String::new())),
+            (EResult::difference("hi", "changed.txt", "/a/changed.txt", "/e/changed.txt", vec![]), Ok(String::new())),
+            (EResult::actual_not_found("hi", "stale.txt", "/a/stale.txt", "/e/stale.txt"), Ok(String::new())),
+            (
+                EResult::difference("hi", "locked.txt", "/a/locked.txt", "/e/locked.txt", vec![]),
+                Err(IoError::other("permission denied")),
+            ),
+            (EResult::io_error("hi", "broken.txt", IoError::other("disk full")), Ok(String::new())),
+            (EResult::duplicate_snapshot("hi", "twice.txt", location(1), location(2)), Ok(String::new())),
+        ];
+
+        let summary = print_promotion("hi", results, false);
+        assert_eq!(
+            summary,
+            PromotionSummary { created: 1, updated: 1, deleted: 1, failed: 1, skipped: 2 }
+        );
+        assert_eq!(summary.promoted(), 3);
+    }
+
+    #[test]
+    fn promotions_with_skipped_files_only_succeed_when_errors_are_allowed() {
+        let skipped = PromotionSummary { created: 1, skipped: 1, ..Default::default() };
+        assert!(!print_promotion_summary(&skipped, false));
+        assert!(print_promotion_summary(&skipped, true));
+
+        let failed = PromotionSummary { updated: 1, failed: 1, ..Default::default() };
+        assert!(!print_promotion_summary(&failed, true));
+
+        let clean = PromotionSummary { created: 2, ..Default::default() };
+        assert!(print_promotion_summary(&clean, false));
+    }
+}
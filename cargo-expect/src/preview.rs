@@ -0,0 +1,133 @@
+use colored::*;
+use expectation_shared::promote::{plan, Plan};
+use expectation_shared::Result as EResult;
+use image::RgbaImage;
+use std::fs;
+use std::path::Path;
+
+/// What the contents of a file look like, for describing a change to it.
+enum Contents {
+    Text(String),
+    Image(RgbaImage),
+    Binary(usize),
+}
+
+fn load(path: &Path) -> Option<Contents> {
+    let bytes = fs::read(path).ok()?;
+    if let Ok(image) = image::load_from_memory(&bytes) {
+        return Some(Contents::Image(image.to_rgba8()));
+    }
+    Some(match String::from_utf8(bytes) {
+        Ok(text) => Contents::Text(text),
+        Err(e) => Contents::Binary(e.into_bytes().len()),
+    })
+}
+
+fn describe(contents: &Contents) -> String {
+    match contents {
+        Contents::Text(text) => format!("{} lines", text.lines().count()),
+        Contents::Image(image) => format!("{}x{} image", image.width(), image.height()),
+        Contents::Binary(len) => format!("{} bytes", len),
+    }
+}
+
+/// Summarizes how `expected` would change if it were replaced by `actual`.
+fn stats(actual: &Contents, expected: &Contents) -> String {
+    match (actual, expected) {
+        (Contents::Text(a), Contents::Text(e)) => {
+            let mut added = 0;
+            let mut removed = 0;
+            for line in diff::lines(a, e) {
+                match line {
+                    diff::Result::Left(_) => added += 1,
+                    diff::Result::Right(_) => removed += 1,
+                    diff::Result::Both(..) => {}
+                }
+            }
+            format!("+{} -{} lines", added, removed)
+        }
+        (Contents::Image(a), Contents::Image(e)) if a.dimensions() == e.dimensions() => {
+            let changed = a.pixels().zip(e.pixels()).filter(|(a, e)| a != e).count();
+            format!("{} of {} pixels changed", changed, a.width() * a.height())
+        }
+        (a, e) => format!("{} -> {}", describe(e), describe(a)),
+    }
+}
+
+/// Describes what promoting `result` would do, without doing it.
+fn preview(result: &EResult) -> Option<String> {
+    let unreadable = || "unreadable".to_string();
+    match plan(&result.kind) {
+        Plan::Nothing => None,
+        Plan::Copy { from, to } if to.exists() => {
+            let stats = match (load(from), load(to)) {
+                (Some(actual), Some(expected)) => stats(&actual, &expected),
+                _ => unreadable(),
+            };
+            Some(format!("{} ({})", "replace".yellow(), stats))
+        }
+        Plan::Copy { from, .. } => {
+            let stats = load(from).map(|c| describe(&c)).unwrap_or_else(unreadable);
+            Some(format!("{} ({})", "create".green(), stats))
+        }
+        Plan::Remove(path) => {
+            let stats = load(path).map(|c| describe(&c)).unwrap_or_else(unreadable);
+            Some(format!("{} ({})", "remove".red(), stats))
+        }
+    }
+}
+
+/// Prints what promoting a test's results would do, returning how many
+/// files would change.
+pub fn print_plan(name: &str, results: &[EResult]) -> usize {
+    let planned: Vec<_> = results
+        .iter()
+        .filter_map(|r| preview(r).map(|p| (r, p)))
+        .collect();
+    if planned.is_empty() {
+        return 0;
+    }
+
+    println!("{} {}", "◼".yellow(), name);
+    for (result, preview) in &planned {
+        println!("  {} {} ❯ {}", "☛".yellow(), result.file_name.to_string_lossy(), preview);
+    }
+    planned.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgba;
+
+    fn text(s: &str) -> Contents {
+        Contents::Text(s.to_owned())
+    }
+
+    fn image(width: u32, height: u32, changed: u32) -> Contents {
+        let mut image = RgbaImage::new(width, height);
+        for (i, pixel) in image.pixels_mut().enumerate() {
+            if (i as u32) < changed {
+                *pixel = Rgba([255, 0, 0, 255]);
+            }
+        }
+        Contents::Image(image)
+    }
+
+    #[test]
+    fn text_stats_count_lines_added_by_the_actual_file() {
+        assert_eq!(stats(&text("a\nb\nc\nd\n"), &text("a\nx\n")), "+3 -1 lines");
+        assert_eq!(stats(&text("a\n"), &text("a\n")), "+0 -0 lines");
+    }
+
+    #[test]
+    fn image_stats_count_changed_pixels() {
+        assert_eq!(stats(&image(4, 2, 3), &image(4, 2, 0)), "3 of 8 pixels changed");
+    }
+
+    #[test]
+    fn stats_describe_both_sides_when_they_cant_be_compared() {
+        assert_eq!(stats(&image(4, 2, 0), &image(2, 2, 0)), "2x2 image -> 4x2 image");
+        assert_eq!(stats(&Contents::Binary(3), &text("a\nb")), "2 lines -> 3 bytes");
+    }
+}
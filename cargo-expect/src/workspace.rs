@@ -0,0 +1,128 @@
+use expectation_shared::config::Config;
+use serde_json::Value;
+use std::io::{Error as IoError, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The directories of the crates whose tests `cargo test` runs in `dir`: the
+/// crate that `dir` belongs to, or the default members of the workspace if
+/// `dir` is the root of a virtual one.
+fn crate_dirs(dir: &Path) -> IoResult<Vec<PathBuf>> {
+    let output = Command::new("cargo")
+        .current_dir(dir)
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .output()?;
+    if !output.status.success() {
+        return Err(IoError::other(format!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout).map_err(IoError::other)?;
+    Ok(parse_crate_dirs(&metadata, &dir.canonicalize()?))
+}
+
+/// Picks the crate directories out of the output of `cargo metadata`.
+fn parse_crate_dirs(metadata: &Value, dir: &Path) -> Vec<PathBuf> {
+    let packages = metadata["packages"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    let dirs: Vec<_> = packages
+        .iter()
+        .filter_map(|package| {
+            let manifest = Path::new(package["manifest_path"].as_str()?);
+            Some((package["id"].as_str()?, manifest.parent()?.to_owned()))
+        })
+        .collect();
+
+    // `cargo test` in a crate of the workspace only runs that crate, which
+    // is the innermost one if crates are nested.
+    let own = dirs
+        .iter()
+        .filter(|(_, d)| dir.starts_with(d))
+        .max_by_key(|(_, d)| d.components().count());
+    if let Some((_, own)) = own {
+        return vec![own.clone()];
+    }
+    let members = match metadata["workspace_default_members"].as_array() {
+        Some(members) => members,
+        None => metadata["workspace_members"].as_array().map(Vec::as_slice).unwrap_or(&[]),
+    };
+    let members: Vec<_> = members.iter().filter_map(Value::as_str).collect();
+    dirs.into_iter()
+        .filter(|(id, _)| members.contains(id))
+        .map(|(_, dir)| dir)
+        .collect()
+}
+
+/// The snapshot roots of the crates tested from `dir`, each resolved from
+/// its own configuration as the test run does.
+pub fn snapshot_roots(dir: &Path) -> IoResult<Vec<PathBuf>> {
+    let mut roots = vec![];
+    for dir in crate_dirs(dir)? {
+        roots.push(Config::load(&dir)?.snapshot_root(&dir));
+    }
+    roots.sort();
+    roots.dedup();
+    Ok(roots)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::fs;
+
+    /// A directory under the system's temporary directory that is removed
+    /// when it goes out of scope.
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let dir = ::std::env::temp_dir()
+                .join(format!("cargo-expect-{}-{}", name, ::std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Lays out a virtual workspace whose `nested` member keeps its
+    /// snapshots in `snapshots/nested` and whose `plain` member uses the
+    /// default root.
+    pub fn workspace(name: &str) -> TempDir {
+        let temp = TempDir::new(name);
+        let write = |path: &str, contents: &str| {
+            let path = temp.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("Cargo.toml", "[workspace]\nmembers = [\"nested\", \"plain\"]\nresolver = \"2\"\n");
+        for member in ["nested", "plain"] {
+            write(
+                &format!("{}/Cargo.toml", member),
+                &format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n", member),
+            );
+            write(&format!("{}/src/lib.rs", member), "");
+        }
+        write("nested/expectation.toml", "root = \"snapshots/nested\"\n");
+        temp
+    }
+
+    #[test]
+    fn roots_of_a_workspace_and_of_its_members() {
+        let temp = workspace("roots");
+        let root = &temp.0;
+        assert_eq!(
+            snapshot_roots(root).unwrap(),
+            vec![root.join("nested/snapshots/nested"), root.join("plain/expectation-tests")]
+        );
+        assert_eq!(
+            snapshot_roots(&root.join("nested/src")).unwrap(),
+            vec![root.join("nested/snapshots/nested")]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+/// The name of the configuration file, looked up next to `Cargo.toml`.
+pub const CONFIG_FILE: &str = "expectation.toml";
+
+/// Settings shared by `expectation` and `cargo-expect`.
+///
+/// They are read from `expectation.toml`, or if that doesn't exist, from the
+/// `[package.metadata.expectation]` table of `Cargo.toml`:
+///
+/// ```toml
+/// root = "snapshots"
+/// filetypes = ["txt", "svg"]
+///
+/// [tolerance]
+/// relative = 1e-9
+///
+/// [[redactions]]
+/// pattern = "\\d{4}-\\d{2}-\\d{2}"
+/// replacement = "<date>"
+///
+/// [report]
+/// json = "target/expectation-report.json"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The directory holding the `expected`, `actual` and `diff` folders,
+    /// relative to the crate.
+    pub root: PathBuf,
+    /// Only files ending in one of these are compared or promoted, unless
+    /// `--filetypes` is given.  Empty means every file.
+    pub filetypes: Vec<String>,
+    pub tolerance: Tolerance,
+    /// Applied to text snapshots before they are written.  Patterns are
+    /// matched a line at a time, so they can't span lines.
+    pub redactions: Vec<Redaction>,
+    pub report: Report,
+}
+
+/// Tolerances used by the writers that don't take one explicitly.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tolerance {
+    /// Allowed absolute difference between numbers in text and CSV files.
+    pub absolute: f64,
+    /// Allowed difference between numbers in text files, as a fraction of
+    /// the larger magnitude.
+    pub relative: f64,
+    /// Allowed distance between numbers in text files, in units in the last
+    /// place.
+    pub ulps: u64,
+    /// Allowed difference between WAV samples, on a scale of -1.0 to 1.0.
+    pub audio: f32,
+}
+
+/// Replaces every match of the regular expression `pattern` with
+/// `replacement`, which may refer to capture groups as `$1` or `$name`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Redaction {
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// Files that `cargo expect run` writes its results to.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Report {
+    /// Every test's results as JSON, relative to the crate.
+    pub json: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            root: PathBuf::from("expectation-tests"),
+            filetypes: vec![],
+            tolerance: Tolerance::default(),
+            redactions: vec![],
+            report: Report::default(),
+        }
+    }
+}
+
+fn invalid(path: &Path, e: impl ::std::fmt::Display) -> IoError {
+    IoError::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.to_string_lossy(), e),
+    )
+}
+
+impl Config {
+    /// Loads the configuration for the crate in `dir`, falling back to the
+    /// defaults if there is none.
+    pub fn load(dir: &Path) -> IoResult<Config> {
+        let path = dir.join(CONFIG_FILE);
+        if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            return Config::parse(&contents).map_err(|e| invalid(&path, e));
+        }
+
+        let path = dir.join("Cargo.toml");
+        if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            return Config::from_manifest(&contents).map_err(|e| invalid(&path, e));
+        }
+
+        Ok(Config::default())
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ::toml::de::Error> {
+        ::toml::from_str(contents)
+    }
+
+    /// Reads `[package.metadata.expectation]` out of a `Cargo.toml`.
+    pub fn from_manifest(contents: &str) -> Result<Config, ::toml::de::Error> {
+        let manifest: ::toml::Table = ::toml::from_str(contents)?;
+        let table = manifest
+            .get("package")
+            .and_then(|p| p.get("metadata"))
+            .and_then(|m| m.get("expectation"));
+        match table {
+            Some(table) => table.clone().try_into(),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// The snapshot directory of the crate in `crate_dir`, which can be
+    /// overridden with the `EXPECTATION_ROOT` environment variable.
+    pub fn snapshot_root(&self, crate_dir: &Path) -> PathBuf {
+        match ::std::env::var_os("EXPECTATION_ROOT") {
+            Some(root) => crate_dir.join(root),
+            None => crate_dir.join(&self.root),
+        }
+    }
+
+    /// Whether `file` should be compared, given the `--filetypes` that were
+    /// asked for on the command line, if any.
+    pub fn accepts(&self, file: &Path, filetypes: Option<&[String]>) -> bool {
+        let filetypes = filetypes.unwrap_or(&self.filetypes);
+        filetypes.is_empty()
+            || filetypes
+                .iter()
+                .any(|ending| file.to_str().map(|f| f.ends_with(ending.as_str())).unwrap_or(false))
+    }
+}
@@ -1,10 +1,9 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::{create_dir_all, File};
 use std::io::{BufRead, Cursor, Result as IoResult, Seek, Write};
 use std::io::{BufReader, BufWriter, Error as IoError, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 pub trait ReadSeek: Seek + BufRead {}
 impl<R: BufRead + Seek> ReadSeek for R {}
@@ -17,15 +16,41 @@ pub struct RealFileSystem {
 #[derive(Clone, Debug)]
 pub struct FakeFileSystem {
     root: PathBuf,
-    mapping: Rc<RefCell<HashMap<PathBuf, Vec<u8>>>>,
+    mapping: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
 }
 
-pub trait FileSystem {
+struct FakeFile {
+    path: PathBuf,
+    mapping: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl Write for FakeFile {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.mapping
+            .lock()
+            .unwrap()
+            .entry(self.path.clone())
+            .or_default()
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+pub trait FileSystem: Send + Sync {
     fn duplicate(&self) -> Box<dyn FileSystem>;
     fn subsystem(&self, path: &Path) -> Box<dyn FileSystem>;
     fn exists(&self, path: &Path) -> bool;
     fn read(&self, path: &Path, f: &mut dyn FnMut(&mut dyn ReadSeek) -> IoResult<()>) -> IoResult<()>;
     fn write(&self, path: &Path, f: &mut dyn FnMut(&mut dyn Write) -> IoResult<()>) -> IoResult<()>;
+    /// Opens `path` for writing, so that a file can be written piece by piece.
+    fn create(&self, path: &Path) -> IoResult<Box<dyn Write + Send>>;
+    fn rename(&self, from: &Path, to: &Path) -> IoResult<()>;
+    /// Creates the directory `path` along with its parents, if they don't
+    /// exist.
+    fn create_dir_all(&self, path: &Path) -> IoResult<()>;
     fn full_path_for(&self, path: &Path) -> PathBuf;
     fn files(&self) -> Vec<PathBuf>;
     fn remove(&self, path: &Path) -> IoResult<()>;
@@ -39,13 +64,27 @@ pub trait FileSystem {
             })
         })
     }
+    /// Removes the directory `path` and everything in it.
+    fn remove_dir_all(&self, path: &Path) -> IoResult<()> {
+        let dir = self.subsystem(path);
+        for file in dir.files() {
+            dir.remove(&file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for FakeFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FakeFileSystem {
     pub fn new() -> Self {
         FakeFileSystem {
             root: PathBuf::from("/"),
-            mapping: Rc::new(RefCell::new(HashMap::new())),
+            mapping: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -96,6 +135,26 @@ impl FileSystem for RealFileSystem {
         }
     }
 
+    fn create(&self, path: &Path) -> IoResult<Box<dyn Write + Send>> {
+        let path = self.root.join(path);
+        create_dir_all(path.parent().unwrap())?;
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> IoResult<()> {
+        let to = self.root.join(to);
+        create_dir_all(to.parent().unwrap())?;
+        ::std::fs::rename(self.root.join(from), to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> IoResult<()> {
+        create_dir_all(self.root.join(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> IoResult<()> {
+        ::std::fs::remove_dir_all(self.root.join(path))
+    }
+
     fn full_path_for(&self, path: &Path) -> PathBuf {
         self.root.join(path)
     }
@@ -124,19 +183,19 @@ impl FileSystem for FakeFileSystem {
 
     fn exists(&self, path: &Path) -> bool {
         let path = self.root.join(path);
-        self.mapping.borrow().contains_key(&path)
+        self.mapping.lock().unwrap().contains_key(&path)
     }
 
     fn remove(&self, path: &Path) -> IoResult<()> {
         let path = self.root.join(path);
-        self.mapping.borrow_mut().remove(&path);
+        self.mapping.lock().unwrap().remove(&path);
         Ok(())
     }
 
     fn read(&self, path: &Path, f: &mut dyn FnMut(&mut dyn ReadSeek) -> IoResult<()>) -> IoResult<()> {
         let path = self.root.join(path);
 
-        let contents = match self.mapping.borrow().get(&path) {
+        let contents = match self.mapping.lock().unwrap().get(&path) {
             Some(contents) => contents.clone(),
             None => {
                 return Err(IoError::new(
@@ -155,7 +214,35 @@ impl FileSystem for FakeFileSystem {
         let mut contents = vec![];
         f(&mut contents)?;
 
-        self.mapping.borrow_mut().insert(path, contents);
+        self.mapping.lock().unwrap().insert(path, contents);
+        Ok(())
+    }
+
+    fn create(&self, path: &Path) -> IoResult<Box<dyn Write + Send>> {
+        let path = self.root.join(path);
+        self.mapping.lock().unwrap().insert(path.clone(), vec![]);
+        Ok(Box::new(FakeFile {
+            path,
+            mapping: self.mapping.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> IoResult<()> {
+        let mut mapping = self.mapping.lock().unwrap();
+        match mapping.remove(&self.root.join(from)) {
+            Some(contents) => {
+                mapping.insert(self.root.join(to), contents);
+                Ok(())
+            }
+            None => Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("{:?} does not exist", from),
+            )),
+        }
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> IoResult<()> {
+        // Only files are stored, and they can be written anywhere.
         Ok(())
     }
 
@@ -166,7 +253,8 @@ impl FileSystem for FakeFileSystem {
     fn files(&self) -> Vec<PathBuf> {
         let root = self.root.clone();
         self.mapping
-            .borrow()
+            .lock()
+            .unwrap()
             .keys()
             .filter_map(|p| p.strip_prefix(&root).ok())
             .map(|p| p.into())
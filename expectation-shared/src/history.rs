@@ -0,0 +1,400 @@
+use super::filesystem::FileSystem;
+use super::index;
+use super::{Result as EResult, ResultKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, Error as IoError, ErrorKind, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory, relative to the snapshot root, holding one directory per
+/// promotion batch.
+pub const HISTORY_DIR: &str = ".history";
+
+/// What a promotion did to an expected file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// The expected file didn't exist before.
+    Created,
+    /// The expected file was overwritten.
+    Replaced,
+    /// The expected file was deleted.
+    Removed,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Action::Created => "created",
+            Action::Replaced => "replaced",
+            Action::Removed => "removed",
+        })
+    }
+}
+
+impl Action {
+    fn parse(s: &str) -> Option<Action> {
+        match s {
+            "created" => Some(Action::Created),
+            "replaced" => Some(Action::Replaced),
+            "removed" => Some(Action::Removed),
+            _ => None,
+        }
+    }
+}
+
+/// One promoted file.  The original contents of replaced and removed files
+/// are kept in the batch's `files` directory under the same relative path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub action: Action,
+    /// The expected file, relative to the snapshot root.
+    pub file: PathBuf,
+}
+
+struct Batch {
+    /// The snapshot root.
+    fs: Box<dyn FileSystem>,
+    number: u32,
+    entries: Vec<Entry>,
+}
+
+/// Records the expected files that a run of promotions is about to change,
+/// so that `undo` can put them back.  Every snapshot root that is touched
+/// gets its own numbered batch.  A batch belongs to a run, and a `History`
+/// of the same run, like another test process of one `cargo test`, adds to
+/// it instead of starting a new one as long as it is the latest batch.
+///
+/// Each line of a batch's `journal` file holds the timestamp, the action and
+/// the file, separated by spaces, and its `run` file holds the run.
+pub struct History {
+    run: String,
+    batches: BTreeMap<PathBuf, Batch>,
+}
+
+fn batch_dir(batch: u32) -> PathBuf {
+    Path::new(HISTORY_DIR).join(batch.to_string())
+}
+
+/// The batch numbers recorded in the snapshot root `fs`, oldest first.
+pub fn batches(fs: &dyn FileSystem) -> Vec<u32> {
+    let mut batches: Vec<u32> = fs
+        .subsystem(Path::new(HISTORY_DIR))
+        .files()
+        .iter()
+        .filter_map(|f| f.components().next())
+        .filter_map(|c| c.as_os_str().to_str().and_then(|n| n.parse().ok()))
+        .collect();
+    batches.sort();
+    batches.dedup();
+    batches
+}
+
+/// The run that a batch belongs to.
+pub fn run_of(fs: &dyn FileSystem, batch: u32) -> Option<String> {
+    let mut run = String::new();
+    fs.read(&batch_dir(batch).join("run"), &mut |r| r.read_to_string(&mut run).map(|_| ()))
+        .ok()
+        .map(|_| run)
+}
+
+/// Reads the journal of a batch, which is empty until something is recorded.
+pub fn entries(fs: &dyn FileSystem, batch: u32) -> IoResult<Vec<Entry>> {
+    let journal = batch_dir(batch).join("journal");
+    if !fs.exists(&journal) {
+        return Ok(vec![]);
+    }
+    let mut entries = vec![];
+    fs.read(&journal, &mut |r| {
+        for line in r.lines() {
+            let line = line?;
+            let mut parts = line.splitn(3, ' ');
+            match (
+                parts.next().and_then(|t| t.parse().ok()),
+                parts.next().and_then(Action::parse),
+                parts.next(),
+            ) {
+                (Some(timestamp), Some(action), Some(file)) => entries.push(Entry {
+                    timestamp,
+                    action,
+                    file: file.into(),
+                }),
+                _ => {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid journal entry in batch {}: {:?}", batch, line),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+impl History {
+    /// Starts a history of its own run.
+    pub fn new() -> History {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        History::for_run(format!("{}-{}", ::std::process::id(), nanos))
+    }
+
+    /// Starts a history that adds to the latest batch of each snapshot root
+    /// if it was recorded for `run`.
+    pub fn for_run<S: Into<String>>(run: S) -> History {
+        History {
+            run: run.into(),
+            batches: BTreeMap::new(),
+        }
+    }
+
+    /// Saves what promoting `result` is about to overwrite or remove.  Must
+    /// be called before the promotion, with the filesystem it is promoted in.
+    pub fn record(&mut self, filesystem: &dyn FileSystem, result: &EResult) -> IoResult<()> {
+        let (expected, action) = match &result.kind {
+            ResultKind::ExpectedNotFound(double) => (&double.expected, Action::Created),
+            ResultKind::Difference(tripple) => (&tripple.expected, Action::Replaced),
+            ResultKind::ActualNotFound(double) => (&double.expected, Action::Removed),
+            _ => return Ok(()),
+        };
+        let root = match index::snapshot_root(result) {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        let file = expected
+            .strip_prefix(&root)
+            .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?
+            .to_owned();
+
+        if !self.batches.contains_key(&root) {
+            let fs = filesystem.subsystem(root.strip_prefix("/").unwrap_or(&root));
+            let latest = batches(&*fs).last().copied();
+            let batch = match latest {
+                Some(number) if run_of(&*fs, number).as_deref() == Some(self.run.as_str()) => {
+                    let entries = entries(&*fs, number)?;
+                    Batch { fs, number, entries }
+                }
+                _ => {
+                    let number = latest.map_or(1, |n| n + 1);
+                    let run = self.run.as_bytes();
+                    fs.write(&batch_dir(number).join("run"), &mut |w| w.write_all(run))?;
+                    Batch { fs, number, entries: vec![] }
+                }
+            };
+            self.batches.insert(root.clone(), batch);
+        }
+        let batch = self.batches.get_mut(&root).expect("batch was just created");
+        let dir = batch_dir(batch.number);
+
+        // Undoing the batch puts back the file as it was before the run.
+        if batch.entries.iter().any(|e| e.file == file) {
+            return Ok(());
+        }
+
+        if action != Action::Created {
+            batch.fs.copy(&file, &dir.join("files").join(&file))?;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        batch.entries.push(Entry { timestamp, action, file });
+
+        // The journal is rewritten every time so that it is complete even if
+        // the promotion is interrupted.
+        let entries = &batch.entries;
+        batch.fs.write(&dir.join("journal"), &mut |journal| {
+            for entry in entries {
+                writeln!(journal, "{} {} {}", entry.timestamp, entry.action, entry.file.to_string_lossy())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The snapshot roots and batch numbers that were recorded.
+    pub fn recorded(&self) -> Vec<(PathBuf, u32)> {
+        self.batches
+            .iter()
+            .map(|(root, batch)| (root.clone(), batch.number))
+            .collect()
+    }
+}
+
+/// Puts back the expected files changed by `batch`, or by the latest batch,
+/// in the snapshot root `fs` and deletes it from the history.  Returns the
+/// batch number and what was undone.
+///
+/// Only the latest batch can be undone: an older one would overwrite what
+/// the batches after it promoted, and their journals would no longer match
+/// the files.
+pub fn undo(fs: &dyn FileSystem, batch: Option<u32>) -> IoResult<(u32, Vec<Entry>)> {
+    let recorded = batches(fs);
+    let latest = *recorded
+        .last()
+        .ok_or_else(|| IoError::new(ErrorKind::NotFound, "there are no promotions to undo"))?;
+    let batch = batch.unwrap_or(latest);
+    if !recorded.contains(&batch) {
+        return Err(IoError::new(ErrorKind::NotFound, format!("there is no batch {}", batch)));
+    }
+    if batch != latest {
+        let newer: Vec<_> = recorded
+            .iter()
+            .filter(|&&b| b > batch)
+            .map(|b| b.to_string())
+            .collect();
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "batch {} was followed by batch {}; undo those first",
+                batch,
+                newer.join(", ")
+            ),
+        ));
+    }
+    let dir = batch_dir(batch);
+    let entries = entries(fs, batch)?;
+    for entry in entries.iter().rev() {
+        match entry.action {
+            Action::Created => {
+                if fs.exists(&entry.file) {
+                    fs.remove(&entry.file)?;
+                }
+            }
+            Action::Replaced | Action::Removed => {
+                fs.copy(&dir.join("files").join(&entry.file), &entry.file)?;
+            }
+        }
+    }
+    fs.remove_dir_all(&dir)?;
+    Ok((batch, entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filesystem::FakeFileSystem;
+    use crate::promote::promote;
+
+    fn write(fs: &FakeFileSystem, path: &str, contents: &str) {
+        fs.write(Path::new(path), &mut |w| w.write_all(contents.as_bytes()))
+            .unwrap();
+    }
+
+    fn read(fs: &FakeFileSystem, path: &str) -> Option<String> {
+        let mut contents = String::new();
+        fs.read(Path::new(path), &mut |r| r.read_to_string(&mut contents).map(|_| ()))
+            .ok()
+            .map(|_| contents)
+    }
+
+    #[test]
+    fn promotions_can_be_undone() {
+        let fs = FakeFileSystem::new();
+        write(&fs, "/root/actual/hi/new.txt", "new");
+        write(&fs, "/root/actual/hi/changed.txt", "after");
+        write(&fs, "/root/expected/hi/changed.txt", "before");
+        write(&fs, "/root/expected/hi/stale.txt", "stale");
+        let results = vec![
+            EResult::expected_not_found("hi", "new.txt", "/root/actual/hi/new.txt", "/root/expected/hi/new.txt"),
+            EResult::difference("hi", "changed.txt", "/root/actual/hi/changed.txt", "/root/expected/hi/changed.txt", vec![]),
+            EResult::actual_not_found("hi", "stale.txt", "/root/actual/hi/stale.txt", "/root/expected/hi/stale.txt"),
+        ];
+
+        let mut history = History::new();
+        for result in &results {
+            history.record(&fs, result).unwrap();
+            promote(&result.kind, fs.duplicate()).unwrap();
+        }
+        assert_eq!(history.recorded(), vec![(PathBuf::from("/root"), 1)]);
+        assert_eq!(read(&fs, "/root/expected/hi/new.txt").as_deref(), Some("new"));
+        assert_eq!(read(&fs, "/root/expected/hi/changed.txt").as_deref(), Some("after"));
+        assert_eq!(read(&fs, "/root/expected/hi/stale.txt"), None);
+
+        let root = fs.subsystem(Path::new("root"));
+        let (batch, entries) = undo(&*root, None).unwrap();
+        assert_eq!(batch, 1);
+        assert_eq!(
+            entries.iter().map(|e| e.action).collect::<Vec<_>>(),
+            vec![Action::Created, Action::Replaced, Action::Removed]
+        );
+        assert_eq!(read(&fs, "/root/expected/hi/new.txt"), None);
+        assert_eq!(read(&fs, "/root/expected/hi/changed.txt").as_deref(), Some("before"));
+        assert_eq!(read(&fs, "/root/expected/hi/stale.txt").as_deref(), Some("stale"));
+        assert_eq!(batches(&*root), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn only_the_latest_promotion_can_be_undone() {
+        let fs = FakeFileSystem::new();
+        let promote_once = |contents: &str| {
+            write(&fs, "/root/actual/hi/foo.txt", contents);
+            let result = EResult::difference(
+                "hi",
+                "foo.txt",
+                "/root/actual/hi/foo.txt",
+                "/root/expected/hi/foo.txt",
+                vec![],
+            );
+            let mut history = History::new();
+            history.record(&fs, &result).unwrap();
+            promote(&result.kind, fs.duplicate()).unwrap();
+        };
+        write(&fs, "/root/expected/hi/foo.txt", "one");
+        promote_once("two");
+        promote_once("three");
+        let root = fs.subsystem(Path::new("root"));
+        assert_eq!(batches(&*root), vec![1, 2]);
+
+        assert_eq!(undo(&*root, Some(1)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(undo(&*root, Some(3)).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(read(&fs, "/root/expected/hi/foo.txt").as_deref(), Some("three"));
+
+        assert_eq!(undo(&*root, Some(2)).unwrap().0, 2);
+        assert_eq!(undo(&*root, Some(1)).unwrap().0, 1);
+        assert_eq!(read(&fs, "/root/expected/hi/foo.txt").as_deref(), Some("one"));
+    }
+
+    #[test]
+    fn histories_of_one_run_share_a_batch() {
+        let fs = FakeFileSystem::new();
+        let promote_in = |run: &str, file: &str, contents: &str| {
+            let actual = format!("/root/actual/hi/{}", file);
+            let expected = format!("/root/expected/hi/{}", file);
+            write(&fs, &actual, contents);
+            let result = EResult::difference("hi", file, &actual, &expected, vec![]);
+            let mut history = History::for_run(run);
+            history.record(&fs, &result).unwrap();
+            promote(&result.kind, fs.duplicate()).unwrap();
+            history.recorded()
+        };
+        write(&fs, "/root/expected/hi/a.txt", "a");
+        write(&fs, "/root/expected/hi/b.txt", "b");
+        assert_eq!(promote_in("one", "a.txt", "a2"), vec![(PathBuf::from("/root"), 1)]);
+        assert_eq!(promote_in("one", "b.txt", "b2"), vec![(PathBuf::from("/root"), 1)]);
+        assert_eq!(promote_in("one", "a.txt", "a3"), vec![(PathBuf::from("/root"), 1)]);
+        let root = fs.subsystem(Path::new("root"));
+        assert_eq!(batches(&*root), vec![1]);
+        assert_eq!(run_of(&*root, 1).as_deref(), Some("one"));
+        assert_eq!(entries(&*root, 1).unwrap().len(), 2);
+
+        assert_eq!(promote_in("two", "a.txt", "a4"), vec![(PathBuf::from("/root"), 2)]);
+        assert_eq!(undo(&*root, None).unwrap().0, 2);
+        assert_eq!(read(&fs, "/root/expected/hi/a.txt").as_deref(), Some("a3"));
+
+        let (batch, entries) = undo(&*root, None).unwrap();
+        assert_eq!((batch, entries.len()), (1, 2));
+        assert_eq!(read(&fs, "/root/expected/hi/a.txt").as_deref(), Some("a"));
+        assert_eq!(read(&fs, "/root/expected/hi/b.txt").as_deref(), Some("b"));
+    }
+}
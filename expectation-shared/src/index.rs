@@ -0,0 +1,140 @@
+use super::filesystem::FileSystem;
+use super::{Result as EResult, ResultKind};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+/// The name of the index file, relative to the `expectation-tests` directory.
+pub const INDEX_FILE: &str = ".index";
+
+/// The digests of an actual/expected pair that compared equal, and the
+/// comparison that they were equal under.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub actual: String,
+    pub expected: String,
+    pub comparator: String,
+}
+
+/// Records which actual/expected pairs compared equal, keyed by
+/// `<test>/<file>`, so that the comparison can be skipped if neither file
+/// has changed since.
+///
+/// Each line of the index file holds the actual digest, the expected digest,
+/// the comparator and the key, separated by spaces.
+#[derive(Debug, Default)]
+pub struct Index {
+    entries: BTreeMap<String, Entry>,
+    changes: Vec<(String, Option<Entry>)>,
+}
+
+pub fn key(test_name: &str, file: &Path) -> String {
+    format!("{}/{}", test_name, file.to_string_lossy())
+}
+
+/// Condenses the description of a comparison, which is free-form text, into
+/// an id that fits in the index file.
+pub fn comparator_id(description: &str) -> String {
+    let hash = ::blake3::hash(description.as_bytes()).to_hex();
+    hash[..16].to_owned()
+}
+
+pub fn digest(fs: &dyn FileSystem, path: &Path) -> IoResult<String> {
+    let mut hasher = ::blake3::Hasher::new();
+    fs.read(path, &mut |r| ::std::io::copy(r, &mut hasher).map(|_| ()))?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+impl Index {
+    /// Loads the index from `fs`, starting from an empty one if it is
+    /// missing or unreadable.
+    pub fn load(fs: &dyn FileSystem) -> Index {
+        let mut index = Index::default();
+        let _ = fs.read(Path::new(INDEX_FILE), &mut |r| {
+            for line in r.lines() {
+                let line = line?;
+                let mut parts = line.splitn(4, ' ');
+                if let (Some(actual), Some(expected), Some(comparator), Some(key)) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                {
+                    index.entries.insert(
+                        key.to_owned(),
+                        Entry {
+                            actual: actual.to_owned(),
+                            expected: expected.to_owned(),
+                            comparator: comparator.to_owned(),
+                        },
+                    );
+                }
+            }
+            Ok(())
+        });
+        index
+    }
+
+    pub fn save(&self, fs: &dyn FileSystem) -> IoResult<()> {
+        fs.write(Path::new(INDEX_FILE), &mut |w| {
+            for (key, entry) in &self.entries {
+                writeln!(
+                    w,
+                    "{} {} {} {}",
+                    entry.actual, entry.expected, entry.comparator, key
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether the files were last compared equal with the same comparator;
+    /// a different comparator might not consider them equal.
+    pub fn is_unchanged(&self, key: &str, actual: &str, expected: &str, comparator: &str) -> bool {
+        match self.entries.get(key) {
+            Some(entry) => {
+                entry.actual == actual && entry.expected == expected && entry.comparator == comparator
+            }
+            None => false,
+        }
+    }
+
+    pub fn record(&mut self, key: String, actual: String, expected: String, comparator: String) {
+        let entry = Entry {
+            actual,
+            expected,
+            comparator,
+        };
+        if self.entries.get(&key) != Some(&entry) {
+            self.entries.insert(key.clone(), entry.clone());
+            self.changes.push((key, Some(entry)));
+        }
+    }
+
+    pub fn forget(&mut self, key: String) {
+        if self.entries.remove(&key).is_some() {
+            self.changes.push((key, None));
+        }
+    }
+
+    /// Applies the changes made to `other` since it was loaded.
+    pub fn merge(&mut self, other: Index) {
+        for (key, entry) in other.changes {
+            match entry {
+                Some(entry) => self.record(key, entry.actual, entry.expected, entry.comparator),
+                None => self.forget(key),
+            }
+        }
+    }
+}
+
+/// Finds the `expectation-tests` directory that a result's files live in.
+pub fn snapshot_root(result: &EResult) -> Option<PathBuf> {
+    let expected = match &result.kind {
+        ResultKind::ExpectedNotFound(double) | ResultKind::ActualNotFound(double) => {
+            &double.expected
+        }
+        ResultKind::Difference(tripple) => &tripple.expected,
+        _ => return None,
+    };
+    // <root>/expected/<test>/<file>
+    let depth = result.file_name.components().count() + 2;
+    expected.ancestors().nth(depth).map(Path::to_owned)
+}
@@ -2,9 +2,16 @@
 extern crate serde_derive;
 extern crate serde;
 extern crate walkdir;
+extern crate blake3;
+extern crate toml;
 
+pub mod config;
 pub mod filesystem;
+pub mod history;
+pub mod index;
+pub mod promote;
 
+use std::fmt;
 use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -20,6 +27,36 @@ pub struct Tripple {
     pub diffs: Vec<PathBuf>,
 }
 
+/// A place in the source of a test, as captured by `#[track_caller]`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl<'a> From<&'a std::panic::Location<'a>> for Location {
+    fn from(location: &'a std::panic::Location<'a>) -> Self {
+        Location {
+            file: location.file().to_owned(),
+            line: location.line(),
+            column: location.column(),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Duplicate {
+    pub first: Location,
+    pub second: Location,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum ResultKind {
     Ok,
@@ -27,6 +64,11 @@ pub enum ResultKind {
     ActualNotFound(Double),
     Difference(Tripple),
     IoError(String),
+    /// The same file was written more than once in a single test.
+    DuplicateSnapshot(Duplicate),
+    /// A file in the actual directory that the test didn't ask the provider
+    /// for, at the given path.
+    UnexpectedFile(PathBuf),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -34,15 +76,23 @@ pub struct Result {
     pub test_name: String,
     pub file_name: PathBuf,
     pub kind: ResultKind,
+    /// Set when the comparison was skipped because neither file changed
+    /// since they last compared equal.
+    #[serde(default)]
+    pub unchanged: bool,
+    /// Where in the test the file was written, if it was written at all.
+    #[serde(default)]
+    pub location: Option<Location>,
+    /// Identifies how the file was compared, so that the index can tell
+    /// whether a promoted file still matches under the same comparison.
+    #[serde(default)]
+    pub comparator: Option<String>,
 }
 
 
 impl Result {
     pub fn is_ok(&self) -> bool {
-        match &self.kind {
-            ResultKind::Ok => true,
-            _ => false,
-        }
+        matches!(&self.kind, ResultKind::Ok)
     }
 
     pub fn ok<N, P>(name: N, file: P) -> Self
@@ -54,6 +104,28 @@ impl Result {
             test_name: name.into(),
             file_name: file.into(),
             kind: ResultKind::Ok,
+            unchanged: false,
+            location: None,
+            comparator: None,
+        }
+    }
+
+    /// Sets the location of the code that wrote the file.
+    pub fn at(self, location: Location) -> Self {
+        Result {
+            location: Some(location),
+            ..self
+        }
+    }
+
+    pub fn unchanged<N, P>(name: N, file: P) -> Self
+    where
+        N: Into<String>,
+        P: Into<PathBuf>,
+    {
+        Result {
+            unchanged: true,
+            ..Result::ok(name, file)
         }
     }
 
@@ -71,6 +143,9 @@ impl Result {
                 actual: actual.into(),
                 expected: expected.into(),
             }),
+            unchanged: false,
+            location: None,
+            comparator: None,
         }
     }
 
@@ -88,6 +163,9 @@ impl Result {
                 actual: actual.into(),
                 expected: expected.into(),
             }),
+            unchanged: false,
+            location: None,
+            comparator: None,
         }
     }
 
@@ -110,8 +188,42 @@ impl Result {
             kind: ResultKind::Difference(Tripple {
                 actual: actual.into(),
                 expected: expected.into(),
-                diffs: diffs,
+                diffs,
             }),
+            unchanged: false,
+            location: None,
+            comparator: None,
+        }
+    }
+
+    pub fn unexpected_file<N, P1, P2>(name: N, file: P1, actual: P2) -> Self
+    where
+        N: Into<String>,
+        P1: Into<PathBuf>,
+        P2: Into<PathBuf>,
+    {
+        Result {
+            test_name: name.into(),
+            file_name: file.into(),
+            kind: ResultKind::UnexpectedFile(actual.into()),
+            unchanged: false,
+            location: None,
+            comparator: None,
+        }
+    }
+
+    pub fn duplicate_snapshot<N, P>(name: N, file: P, first: Location, second: Location) -> Self
+    where
+        N: Into<String>,
+        P: Into<PathBuf>,
+    {
+        Result {
+            test_name: name.into(),
+            file_name: file.into(),
+            kind: ResultKind::DuplicateSnapshot(Duplicate { first, second }),
+            unchanged: false,
+            location: None,
+            comparator: None,
         }
     }
 
@@ -124,6 +236,9 @@ impl Result {
             test_name: name.into(),
             file_name: file.into(),
             kind: ResultKind::IoError(format!("{:?}", io_error)),
+            unchanged: false,
+            location: None,
+            comparator: None,
         }
     }
 }
@@ -0,0 +1,108 @@
+use super::{Double, Result as EResult, ResultKind, Tripple};
+use super::filesystem::FileSystem;
+use super::history::History;
+use super::index::{self, Index, INDEX_FILE};
+use std::collections::BTreeMap;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+
+/// What promoting a result does to the expected file.
+#[derive(Debug, PartialEq)]
+pub enum Plan<'a> {
+    /// The actual file replaces the expected file, which may not exist yet.
+    Copy { from: &'a Path, to: &'a Path },
+    /// The expected file has no actual file and is removed.
+    Remove(&'a Path),
+    Nothing,
+}
+
+pub fn plan(result: &ResultKind) -> Plan<'_> {
+    match result {
+        ResultKind::IoError(_) |
+        ResultKind::DuplicateSnapshot(_) |
+        ResultKind::UnexpectedFile(_) |
+        ResultKind::Ok => Plan::Nothing,
+        ResultKind::ExpectedNotFound(double) => Plan::Copy {
+            from: &double.actual,
+            to: &double.expected,
+        },
+        ResultKind::ActualNotFound(double) => Plan::Remove(&double.expected),
+        ResultKind::Difference(triple) => Plan::Copy {
+            from: &triple.actual,
+            to: &triple.expected,
+        },
+    }
+}
+
+/// Replaces the expected file with the actual one, or removes the expected
+/// file if there is no actual one, describing what was done.
+pub fn promote(result: &ResultKind, filesystem: Box<dyn FileSystem>) -> IoResult<String> {
+    match plan(result) {
+        Plan::Nothing => Ok("Nothing to do".into()),
+        Plan::Copy { from, to } => {
+            filesystem.copy(from, to)?;
+            Ok(format!("moved {} -> {}", from.to_string_lossy(),
+                                         to.to_string_lossy()))
+        }
+        Plan::Remove(path) => {
+            filesystem.remove(path)?;
+            Ok(format!("removed {}", path.to_string_lossy()))
+        }
+    }
+}
+
+/// Promotes a test's results, saving the expected files in the history
+/// first.  A file whose previous version couldn't be saved isn't promoted.
+pub fn promote_results(
+    results: Vec<EResult>,
+    filesystem: &dyn FileSystem,
+    history: &mut History,
+) -> Vec<(EResult, IoResult<String>)> {
+    let rs: Vec<_> = results
+        .into_iter()
+        .map(|r| {
+            let p = history
+                .record(filesystem, &r)
+                .and_then(|_| promote(&r.kind, filesystem.duplicate()));
+            (r, p)
+        }).collect();
+    update_index(&rs, filesystem);
+    rs
+}
+
+/// Brings the hash index of every snapshot root touched by `results` up to
+/// date with the promoted files, so that the next indexed run knows they
+/// match.  Roots without an index are left alone.
+pub fn update_index(results: &[(EResult, IoResult<String>)], filesystem: &dyn FileSystem) {
+    let mut by_root: BTreeMap<PathBuf, Vec<&EResult>> = BTreeMap::new();
+    for (result, promoted) in results {
+        if promoted.is_ok()
+            && let Some(root) = index::snapshot_root(result)
+        {
+            by_root.entry(root).or_default().push(result);
+        }
+    }
+
+    for (root, results) in by_root {
+        let root_fs = filesystem.subsystem(root.strip_prefix("/").unwrap_or(&root));
+        if !root_fs.exists(INDEX_FILE.as_ref()) {
+            continue;
+        }
+        let mut idx = Index::load(&*root_fs);
+        for result in results {
+            let key = index::key(&result.test_name, &result.file_name);
+            match &result.kind {
+                ResultKind::ExpectedNotFound(Double { expected, .. })
+                | ResultKind::Difference(Tripple { expected, .. }) => {
+                    match (index::digest(filesystem, expected), &result.comparator) {
+                        (Ok(d), Some(comparator)) => idx.record(key, d.clone(), d, comparator.clone()),
+                        _ => idx.forget(key),
+                    }
+                }
+                ResultKind::ActualNotFound(_) => idx.forget(key),
+                _ => {}
+            }
+        }
+        let _ = idx.save(&*root_fs);
+    }
+}
@@ -1,23 +1,139 @@
 use proc_macro::TokenStream;
 use quote::quote;
+use syn::spanned::Spanned;
 use syn::ItemFn;
 
+/// How an `async fn` test body is driven to completion.
+enum Runtime {
+    /// `expectation::block_on`, which needs no extra dependencies.
+    Builtin,
+    /// A current-thread tokio runtime with all drivers enabled.
+    Tokio,
+}
+
+struct Options {
+    runtime: Runtime,
+    /// Where `runtime` was given, if it was.
+    runtime_span: Option<proc_macro2::Span>,
+    /// Overrides the snapshot directory, relative to the crate.
+    root: Option<syn::LitStr>,
+}
+
+fn parse_options(metadata: TokenStream) -> syn::Result<Options> {
+    let mut runtime = Runtime::Builtin;
+    let mut runtime_span = None;
+    let mut root = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("root") {
+            root = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("runtime") {
+            runtime_span = Some(meta.path.span());
+            let value: syn::LitStr = meta.value()?.parse()?;
+            runtime = match value.value().as_str() {
+                "builtin" => Runtime::Builtin,
+                "tokio" => Runtime::Tokio,
+                _ => {
+                    return Err(syn::Error::new(
+                        value.span(),
+                        "unknown runtime, expected \"tokio\" or \"builtin\"",
+                    ))
+                }
+            };
+            Ok(())
+        } else {
+            Err(meta.error("unsupported expectation_test attribute"))
+        }
+    });
+    syn::parse::Parser::parse(parser, metadata)?;
+    Ok(Options { runtime, runtime_span, root })
+}
+
+/// Turns `fn name(provider: Provider)` into a test that compares the files
+/// it writes with their snapshots.  `root = "..."` overrides the snapshot
+/// directory.  An `async fn` is driven by `expectation::block_on`, or by a
+/// tokio runtime with `runtime = "tokio"`:
+///
+/// ```
+/// use expectation::Provider;
+/// use expectation_plugin::expectation_test;
+///
+/// #[expectation_test(runtime = "builtin")]
+/// async fn async_test(provider: Provider) {
+///     let _ = provider.subdir("async");
+/// }
+/// ```
+///
+/// `runtime` only applies to async fns:
+///
+/// ```compile_fail
+/// use expectation::Provider;
+/// use expectation_plugin::expectation_test;
+///
+/// #[expectation_test(runtime = "builtin")]
+/// fn sync_test(provider: Provider) {
+///     let _ = provider.subdir("sync");
+/// }
+/// ```
 #[proc_macro_attribute]
-pub fn expectation_test(_metadata: TokenStream, input: TokenStream) -> TokenStream {
+pub fn expectation_test(metadata: TokenStream, input: TokenStream) -> TokenStream {
+    let options = match parse_options(metadata) {
+        Ok(options) => options,
+        Err(e) => return e.to_compile_error().into(),
+    };
     let item: ItemFn = syn::parse(input).expect("failed to parse input");
+    if let (None, Some(span)) = (item.sig.asyncness, options.runtime_span) {
+        return syn::Error::new(span, "`runtime` only applies to async fns")
+            .to_compile_error()
+            .into();
+    }
     let old_name = &item.sig.ident;
     let new_name_str = format!("expectation_test_{}", old_name);
     let new_name = syn::Ident::new(&new_name_str, old_name.span());
     let old_name_lit = syn::LitStr::new(&new_name_str, old_name.span());
 
+    let root = match &options.root {
+        Some(root) => quote! { ::std::option::Option::Some(#root) },
+        None => quote! { ::std::option::Option::None },
+    };
+
+    let body = if item.sig.asyncness.is_none() {
+        quote! {
+            ::expectation::expect_in(
+                env!("CARGO_MANIFEST_DIR"),
+                #root,
+                #old_name_lit,
+                #old_name,
+            );
+        }
+    } else {
+        let test = quote! {
+            ::expectation::expect_async_in(
+                env!("CARGO_MANIFEST_DIR"),
+                #root,
+                #old_name_lit,
+                #old_name,
+            )
+        };
+        match options.runtime {
+            Runtime::Builtin => quote! {
+                ::expectation::block_on(#test);
+            },
+            Runtime::Tokio => quote! {
+                ::tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("failed to build the tokio runtime")
+                    .block_on(#test);
+            },
+        }
+    };
+
     let output = quote! {
         #[test]
         fn #new_name () {
             #item
-            ::expectation::expect(
-                #old_name_lit,
-                #old_name,
-            );
+            #body
         }
     };
     output.into()
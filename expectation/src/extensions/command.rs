@@ -0,0 +1,130 @@
+use super::super::provider::Provider;
+use super::TextDiffExtension;
+
+use std::io::{Result as IoResult, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+
+/// Values in the output of a command that change from machine to machine,
+/// and are replaced before it is compared.
+#[derive(Clone, Debug, Default)]
+pub struct CommandNormalization {
+    /// Replaces the working directory of the command with `[CWD]`.
+    pub cwd: bool,
+    /// Replaces the values of these environment variables, as the command
+    /// sees them, with `[$NAME]`.
+    pub env: Vec<String>,
+}
+
+pub trait CommandExtension {
+    /// Runs `command` and snapshots what it printed as `name/stdout.txt`
+    /// and `name/stderr.txt`, and how it exited as `name/status.txt`.
+    #[track_caller]
+    fn command<N>(&self, name: N, command: Command) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+    {
+        self.command_with_normalization(name, command, &CommandNormalization::default())
+    }
+
+    /// Like `command`, but with the values in `normalization` replaced in
+    /// the output, so that it is the same wherever the test runs.
+    #[track_caller]
+    fn command_with_normalization<N>(
+        &self,
+        name: N,
+        command: Command,
+        normalization: &CommandNormalization,
+    ) -> IoResult<()>
+    where
+        N: AsRef<Path>;
+}
+
+impl CommandExtension for Provider {
+    #[track_caller]
+    fn command_with_normalization<N>(
+        &self,
+        name: N,
+        mut command: Command,
+        normalization: &CommandNormalization,
+    ) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+    {
+        let name = name.as_ref();
+        let replacements = replacements(&command, normalization)?;
+        let output = command.output()?;
+        let normalize = |bytes: &[u8]| {
+            let mut text = String::from_utf8_lossy(bytes).into_owned();
+            for (value, replacement) in &replacements {
+                text = text.replace(value.as_str(), replacement);
+            }
+            text
+        };
+
+        let mut stdout = self.text_writer(name.join("stdout.txt"));
+        write!(stdout, "{}", normalize(&output.stdout))?;
+        stdout.finish()?;
+
+        let mut stderr = self.text_writer(name.join("stderr.txt"));
+        write!(stderr, "{}", normalize(&output.stderr))?;
+        stderr.finish()?;
+
+        let mut status = self.text_writer(name.join("status.txt"));
+        writeln!(status, "{}", describe_status(output.status))?;
+        status.finish()
+    }
+}
+
+/// The values to replace in the output, longest first so that a value that
+/// contains another one is replaced as a whole.
+fn replacements(command: &Command, normalization: &CommandNormalization) -> IoResult<Vec<(String, String)>> {
+    let mut replacements = vec![];
+    if normalization.cwd {
+        // A relative directory is relative to ours, and the command sees the
+        // directory with its symlinks resolved.
+        let cwd = match command.get_current_dir() {
+            Some(dir) => ::std::env::current_dir()?.join(dir),
+            None => ::std::env::current_dir()?,
+        };
+        let cwd = cwd.canonicalize().unwrap_or(cwd);
+        replacements.push((cwd.to_string_lossy().into_owned(), "[CWD]".to_owned()));
+    }
+    for var in &normalization.env {
+        let set = command
+            .get_envs()
+            .find(|(k, _)| *k == var.as_str())
+            .map(|(_, v)| v.map(|v| v.to_string_lossy().into_owned()));
+        let value = match set {
+            Some(value) => value,
+            None => ::std::env::var(var).ok(),
+        };
+        if let Some(value) = value {
+            replacements.push((value, format!("[${}]", var)));
+        }
+    }
+    replacements.retain(|(value, _)| !value.is_empty());
+    replacements.sort_by_key(|(value, _)| ::std::cmp::Reverse(value.len()));
+    Ok(replacements)
+}
+
+fn describe_status(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exit code: {}", code),
+        None => termination(status),
+    }
+}
+
+#[cfg(unix)]
+fn termination(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match status.signal() {
+        Some(signal) => format!("killed by signal: {}", signal),
+        None => "terminated".to_owned(),
+    }
+}
+
+#[cfg(not(unix))]
+fn termination(_status: ExitStatus) -> String {
+    "terminated".to_owned()
+}
@@ -0,0 +1,126 @@
+use super::super::provider::Provider;
+use super::super::*;
+
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write};
+use std::path::Path;
+
+#[cfg(feature = "image")]
+use super::ImageDiffExtension;
+#[cfg(feature = "text")]
+use super::TextDiffExtension;
+
+/// Extensions of the files that `snapshot_dir` compares as text.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "rs", "toml", "json", "yaml", "yml", "xml", "html", "css", "js", "ts", "csv",
+    "c", "h", "cpp", "hpp", "py", "sh", "svg",
+];
+
+pub trait DirectoryExtension {
+    /// Snapshots every file under the real directory `source` as
+    /// `name/<path in source>`.  Each file is compared as text, as an image
+    /// or byte for byte depending on its extension, so that added, removed
+    /// and changed files are reported one by one.  The sorted list of files
+    /// is snapshotted as `name.tree` too, and its diff summarizes how the
+    /// structure of the directory changed.
+    #[track_caller]
+    fn snapshot_dir<N, P>(&self, name: N, source: P) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        P: AsRef<Path>;
+}
+
+impl DirectoryExtension for Provider {
+    #[track_caller]
+    fn snapshot_dir<N, P>(&self, name: N, source: P) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        let name = name.as_ref();
+        let source = source.as_ref();
+        if !source.is_dir() {
+            return Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("{} is not a directory", source.to_string_lossy()),
+            ));
+        }
+
+        let mut files = RealFileSystem { root: source.into() }.files();
+        files.sort();
+
+        let mut tree = self.tree_writer(&name.with_extension(tree_extension(name)));
+        for file in &files {
+            writeln!(tree, "{}", file.to_string_lossy().replace('\\', "/"))?;
+        }
+        tree.finish()?;
+
+        for file in &files {
+            let mut w = self.file_writer(&name.join(file));
+            ::std::io::copy(&mut File::open(source.join(file))?, &mut w)?;
+            w.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// `name.tree`, keeping anything that looks like an extension in `name`.
+fn tree_extension(name: &Path) -> String {
+    match name.extension() {
+        Some(e) => format!("{}.tree", e.to_string_lossy()),
+        None => "tree".into(),
+    }
+}
+
+/// How `snapshot_dir` compares a file.
+enum Kind {
+    Text,
+    Image,
+    Binary,
+}
+
+fn kind_of(name: &Path) -> Kind {
+    let extension = name
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if extension == "png" {
+        Kind::Image
+    } else if TEXT_EXTENSIONS.contains(&extension.as_str()) {
+        Kind::Text
+    } else {
+        Kind::Binary
+    }
+}
+
+impl Provider {
+    #[cfg(feature = "text")]
+    #[track_caller]
+    fn tree_writer(&self, name: &Path) -> Writer {
+        self.text_writer(name)
+    }
+
+    #[cfg(not(feature = "text"))]
+    #[track_caller]
+    fn tree_writer(&self, name: &Path) -> Writer {
+        self.binary_writer(name)
+    }
+
+    /// Compares text and images with their extensions when those are
+    /// enabled, and everything else byte for byte.
+    #[track_caller]
+    fn file_writer(&self, name: &Path) -> Writer {
+        match kind_of(name) {
+            #[cfg(feature = "text")]
+            Kind::Text => self.text_writer(name),
+            #[cfg(feature = "image")]
+            Kind::Image => self.png_writer(name),
+            _ => self.binary_writer(name),
+        }
+    }
+
+    #[track_caller]
+    fn binary_writer(&self, name: &Path) -> Writer {
+        self.custom_test(name, |a, b| streams_eq(a, b), |_, _, _, _| Ok(()))
+    }
+}
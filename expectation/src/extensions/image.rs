@@ -2,40 +2,44 @@ use super::super::provider::{Provider, WriteRequester};
 use super::super::*;
 use expectation_shared::filesystem::ReadSeek;
 
-use std::io::{BufReader, Result as IoResult};
+use std::io::{BufReader, Error as IoError, Result as IoResult};
 use std::path::Path;
 
 use image::*;
 
 pub trait ImageDiffExtension {
+    #[track_caller]
     fn png_writer<N>(&self, filename: N) -> Writer
     where
         N: AsRef<Path>;
 
+    #[track_caller]
     fn rgb_image<N>(&self, filename: N, image: RgbImage) -> IoResult<()>
     where
         N: AsRef<Path>,
     {
         use image::codecs::png::PngEncoder;
-        let w = self.png_writer(filename);
-        let encoder = PngEncoder::new(w);
-        encoder.write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgb8).unwrap();
-        Ok(())
+        let mut w = self.png_writer(filename);
+        let encoder = PngEncoder::new(&mut w);
+        encoder.write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgb8).map_err(IoError::other)?;
+        w.finish()
     }
 
+    #[track_caller]
     fn rgba_image<N>(&self, filename: N, image: RgbaImage) -> IoResult<()>
     where
         N: AsRef<Path>,
     {
         use image::codecs::png::PngEncoder;
-        let w = self.png_writer(filename);
-        let encoder = PngEncoder::new(w);
-        encoder.write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgba8).unwrap();
-        Ok(())
+        let mut w = self.png_writer(filename);
+        let encoder = PngEncoder::new(&mut w);
+        encoder.write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgba8).map_err(IoError::other)?;
+        w.finish()
     }
 }
 
 impl ImageDiffExtension for Provider {
+    #[track_caller]
     fn png_writer<S>(&self, filename: S) -> Writer
     where
         S: AsRef<Path>,
@@ -107,48 +111,78 @@ fn image_diff<R1: ReadSeek, R2: ReadSeek>(
     let i2 = load(&mut r2, ImageFormat::Png).unwrap();
 
     match (i1, i2) {
-        (DynamicImage::ImageRgb8(i1), DynamicImage::ImageRgb8(i2)) => {
-            if i1.width() != i2.width() || i1.height() != i2.height() {
-                return write_requester.request(path.join("img-size.txt"), |w| {
-                    writeln!(w, "image dimensions are different")?;
-                    writeln!(w, "actual:   width: {} height: {}", i1.width(), i1.height())?;
-                    writeln!(w, "expected: width: {} height: {}", i2.width(), i2.height())?;
-                    Ok(())
-                });
-            }
-            // TODO: implement image diffing
-            Ok(())
-            //panic!("images arent pixel-by-pixel equal");
-        }
+        (DynamicImage::ImageRgb8(i1), DynamicImage::ImageRgb8(i2)) => pixel_diff(
+            &DynamicImage::ImageRgb8(i1).to_rgba8(),
+            &DynamicImage::ImageRgb8(i2).to_rgba8(),
+            path,
+            write_requester,
+        ),
         (DynamicImage::ImageRgba8(i1), DynamicImage::ImageRgba8(i2)) => {
-            if i1.width() != i2.width() || i1.height() != i2.height() {
-                return write_requester.request(path.join("img-size.txt"), |w| {
-                    writeln!(w, "image dimensions are different")?;
-                    writeln!(w, "actual:   width: {} height: {}", i1.width(), i1.height())?;
-                    writeln!(w, "expected: width: {} height: {}", i2.width(), i2.height())?;
-                    Ok(())
-                });
-            }
-            // TODO: implement image diffing
-            Ok(())
-            //panic!("images arent pixel-by-pixel equal");
+            pixel_diff(&i1, &i2, path, write_requester)
         }
         (DynamicImage::ImageRgb8(_), DynamicImage::ImageRgba8(_)) => {
-            return write_requester.request(path.join("img-format.txt"), |w| {
+            write_requester.request(path.join("img-format.txt"), |w| {
                 writeln!(w, "image formats are different")?;
                 writeln!(w, "actual:   RGB8")?;
                 writeln!(w, "expected: RGBA8 (Alpha)")?;
                 Ok(())
-            });
+            })
         }
         (DynamicImage::ImageRgba8(_), DynamicImage::ImageRgb8(_)) => {
-            return write_requester.request(path.join("img-format.txt"), |w| {
+            write_requester.request(path.join("img-format.txt"), |w| {
                 writeln!(w, "image formats are different")?;
                 writeln!(w, "actual:   RGBA8 (Alpha)")?;
                 writeln!(w, "expected: RGB8")?;
                 Ok(())
-            });
+            })
         }
         (_, _) => panic!(),
     }
 }
+
+/// Writes `img-size.txt` if the two images have different dimensions, and
+/// otherwise a `diff.png` where differing pixels are painted red on top of a
+/// faded copy of the expected image.
+pub(crate) fn pixel_diff(
+    actual: &RgbaImage,
+    expected: &RgbaImage,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+) -> IoResult<()> {
+    if actual.dimensions() != expected.dimensions() {
+        return write_requester.request(path.join("img-size.txt"), |w| {
+            writeln!(w, "image dimensions are different")?;
+            writeln!(w, "actual:   width: {} height: {}", actual.width(), actual.height())?;
+            writeln!(w, "expected: width: {} height: {}", expected.width(), expected.height())?;
+            Ok(())
+        });
+    }
+
+    let diff = RgbaImage::from_fn(actual.width(), actual.height(), |x, y| {
+        let a = actual.get_pixel(x, y);
+        let e = expected.get_pixel(x, y);
+        if a == e {
+            let Rgba([r, g, b, _]) = *e;
+            let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+            let faded = (192 + luma / 4) as u8;
+            Rgba([faded, faded, faded, 255])
+        } else {
+            Rgba([255, 0, 0, 255])
+        }
+    });
+
+    write_png(path.join("diff.png"), &diff, write_requester)
+}
+
+pub(crate) fn write_png(
+    path: PathBuf,
+    image: &RgbaImage,
+    write_requester: &mut WriteRequester,
+) -> IoResult<()> {
+    use image::codecs::png::PngEncoder;
+    write_requester.request(path, |w| {
+        PngEncoder::new(w)
+            .write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgba8)
+            .map_err(IoError::other)
+    })
+}
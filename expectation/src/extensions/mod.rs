@@ -8,3 +8,42 @@ pub use self::text::*;
 mod image;
 #[cfg(feature = "image")]
 pub use self::image::*;
+
+#[cfg(feature = "table")]
+mod table;
+#[cfg(feature = "table")]
+pub use self::table::*;
+
+#[cfg(feature = "svg")]
+mod svg;
+#[cfg(feature = "svg")]
+pub use self::svg::*;
+
+#[cfg(feature = "xml")]
+mod xml;
+#[cfg(feature = "xml")]
+pub use self::xml::*;
+
+#[cfg(feature = "wav")]
+mod wav;
+#[cfg(feature = "wav")]
+pub use self::wav::*;
+
+#[cfg(feature = "command")]
+mod command;
+#[cfg(feature = "command")]
+pub use self::command::*;
+
+mod dir;
+pub use self::dir::*;
+
+/// Appends `new_ext` to the extension of `p`, which names the diff of the
+/// file at `p`.
+#[cfg(any(feature = "text", feature = "table", feature = "xml"))]
+pub(crate) fn add_extension(p: &::std::path::Path, new_ext: &str) -> ::std::path::PathBuf {
+    let old_ext = match p.extension() {
+        Some(e) => e.to_string_lossy().into_owned(),
+        None => "".to_owned(),
+    };
+    p.with_extension(format!("{}{}", old_ext, new_ext))
+}
@@ -0,0 +1,233 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::image::{pixel_diff, write_png};
+
+use std::fmt::Write as FmtWrite;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+
+use diff;
+use image::RgbaImage;
+use resvg::{tiny_skia, usvg};
+use roxmltree;
+
+/// Controls how SVG documents are normalized before they are compared.
+#[derive(Clone, Debug)]
+pub struct SvgOptions {
+    /// Number of decimal places that numbers in numeric and geometry
+    /// attributes, like `x`, `d` or `transform`, are rounded to.
+    pub precision: usize,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions { precision: 3 }
+    }
+}
+
+pub trait SvgDiffExtension {
+    #[track_caller]
+    fn svg_writer_with<N>(&self, filename: N, options: SvgOptions) -> Writer
+    where
+        N: AsRef<Path>;
+
+    #[track_caller]
+    fn svg_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.svg_writer_with(filename, SvgOptions::default())
+    }
+
+    #[track_caller]
+    fn svg<N, S>(&self, filename: N, svg: S) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let mut w = self.svg_writer(filename);
+        write!(w, "{}", svg.as_ref())?;
+        w.finish()
+    }
+}
+
+impl SvgDiffExtension for Provider {
+    #[track_caller]
+    fn svg_writer_with<S>(&self, filename: S, options: SvgOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let compare_options = options.clone();
+        let diff_options = options.clone();
+        self.custom_test_with(
+            filename,
+            &options,
+            move |a, b| svg_eq(a, b, &compare_options),
+            move |a, b, c, d| svg_diff(a, b, c, d, &diff_options),
+        )
+    }
+}
+
+/// Attributes that hold numbers or geometry, whose numbers are rounded.
+/// Everything else, like colors, ids and references, is compared exactly,
+/// since rounding would make `#00ff00` and `#000ff0` the same.
+const NUMERIC_ATTRIBUTES: &[&str] = &[
+    "x", "y", "x1", "y1", "x2", "y2", "cx", "cy", "r", "rx", "ry", "fx", "fy", "dx", "dy",
+    "width", "height", "d", "points", "transform", "gradientTransform", "patternTransform",
+    "viewBox", "offset", "opacity", "fill-opacity", "stroke-opacity", "stroke-width",
+    "stroke-dasharray", "stroke-dashoffset", "stroke-miterlimit", "font-size", "refX", "refY",
+    "markerWidth", "markerHeight",
+];
+
+fn round_numbers(value: &str, precision: usize) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit() || c == '.') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let mut end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        // Only a single decimal point belongs to a number; "1.5.5" is two.
+        if let Some(second) = rest[..end].match_indices('.').nth(1).map(|(i, _)| i) {
+            end = second;
+        }
+
+        let token = &rest[..end];
+        match token.parse::<f64>() {
+            Ok(n) => {
+                let rounded = format!("{:.*}", precision, n);
+                let rounded = if rounded.contains('.') {
+                    rounded.trim_end_matches('0').trim_end_matches('.')
+                } else {
+                    &rounded
+                };
+                out.push_str(rounded);
+            }
+            Err(_) => out.push_str(token),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn qualified_name(name: roxmltree::ExpandedName) -> String {
+    match name.namespace() {
+        Some(ns) if ns != "http://www.w3.org/2000/svg" => format!("{{{}}}{}", ns, name.name()),
+        _ => name.name().to_owned(),
+    }
+}
+
+fn write_node(out: &mut String, node: roxmltree::Node, depth: usize, options: &SvgOptions) {
+    let indent = "  ".repeat(depth);
+    if node.is_text() {
+        let text = node.text().unwrap_or("").trim();
+        if !text.is_empty() {
+            let _ = writeln!(out, "{}{:?}", indent, text);
+        }
+        return;
+    }
+    if !node.is_element() {
+        return;
+    }
+
+    let mut attributes: Vec<(String, String)> = node
+        .attributes()
+        .map(|a| {
+            let name = match a.namespace() {
+                Some(ns) => format!("{{{}}}{}", ns, a.name()),
+                None => a.name().to_owned(),
+            };
+            let value = if NUMERIC_ATTRIBUTES.contains(&a.name()) {
+                round_numbers(a.value(), options.precision)
+            } else {
+                a.value().to_owned()
+            };
+            (name, value)
+        }).collect();
+    attributes.sort();
+
+    let _ = write!(out, "{}<{}", indent, qualified_name(node.tag_name()));
+    for (name, value) in attributes {
+        let _ = write!(out, " {}={:?}", name, value);
+    }
+    let _ = writeln!(out, ">");
+    for child in node.children() {
+        write_node(out, child, depth + 1, options);
+    }
+}
+
+/// Parses `svg` and prints it back out with sorted attributes, rounded
+/// numbers, one element per line and no comments or insignificant
+/// whitespace.
+fn normalize(svg: &str, options: &SvgOptions) -> IoResult<String> {
+    let document = roxmltree::Document::parse(svg)
+        .map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+    let mut out = String::new();
+    write_node(&mut out, document.root_element(), 0, options);
+    Ok(out)
+}
+
+fn rasterize(svg: &str) -> Option<RgbaImage> {
+    let tree = usvg::Tree::from_str(svg, &usvg::Options::default()).ok()?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        }).collect();
+    RgbaImage::from_raw(size.width(), size.height(), pixels)
+}
+
+fn svg_eq<R1: Read, R2: Read>(mut r1: R1, mut r2: R2, options: &SvgOptions) -> IoResult<bool> {
+    let mut s1 = String::new();
+    let mut s2 = String::new();
+    r1.read_to_string(&mut s1)?;
+    r2.read_to_string(&mut s2)?;
+
+    Ok(normalize(&s1, options)? == normalize(&s2, options)?)
+}
+
+fn svg_diff<R1: Read, R2: Read>(
+    mut r1: R1,
+    mut r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    options: &SvgOptions,
+) -> IoResult<()> {
+    let mut s1 = String::new();
+    let mut s2 = String::new();
+    r1.read_to_string(&mut s1)?;
+    r2.read_to_string(&mut s2)?;
+
+    let n1 = normalize(&s1, options)?;
+    let n2 = normalize(&s2, options)?;
+    write_requester.request(path.join("structure.diff"), |w| {
+        for diff in diff::lines(&n1, &n2) {
+            match diff {
+                diff::Result::Left(l) => writeln!(w, "+{}", l)?,
+                diff::Result::Both(l, _) => writeln!(w, " {}", l)?,
+                diff::Result::Right(r) => writeln!(w, "-{}", r)?,
+            }
+        }
+        Ok(())
+    })?;
+
+    match (rasterize(&s1), rasterize(&s2)) {
+        (Some(i1), Some(i2)) => {
+            write_png(path.join("actual.png"), &i1, write_requester)?;
+            write_png(path.join("expected.png"), &i2, write_requester)?;
+            pixel_diff(&i1, &i2, path, write_requester)
+        }
+        _ => write_requester.request(path.join("raster.txt"), |w| {
+            writeln!(w, "one of the documents could not be rendered")
+        }),
+    }
+}
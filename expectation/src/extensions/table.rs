@@ -0,0 +1,319 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::add_extension;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult};
+use std::path::Path;
+
+use csv;
+use diff;
+
+/// Controls how two tables are matched up and compared.
+#[derive(Clone, Debug, Default)]
+pub struct TableOptions {
+    /// Columns that identify a row.  When non-empty, rows are matched by
+    /// their key instead of by their position in the table.
+    pub key_columns: Vec<usize>,
+    /// Cells that both parse as numbers are considered equal if they are
+    /// within this distance of each other.
+    pub numeric_tolerance: f64,
+}
+
+pub trait TableDiffExtension {
+    /// The options used when none are given, which take their tolerance
+    /// from the crate's configuration.
+    fn default_table_options(&self) -> TableOptions;
+
+    #[track_caller]
+    fn csv_writer_with<N>(&self, filename: N, options: TableOptions) -> Writer
+    where
+        N: AsRef<Path>;
+
+    #[track_caller]
+    fn csv_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.csv_writer_with(filename, self.default_table_options())
+    }
+
+    #[track_caller]
+    fn csv<N, R, C>(&self, filename: N, rows: R) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        R: IntoIterator,
+        R::Item: IntoIterator<Item = C>,
+        C: Display,
+    {
+        self.csv_with(filename, rows, self.default_table_options())
+    }
+
+    #[track_caller]
+    fn csv_with<N, R, C>(&self, filename: N, rows: R, options: TableOptions) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        R: IntoIterator,
+        R::Item: IntoIterator<Item = C>,
+        C: Display,
+    {
+        let mut w = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(self.csv_writer_with(filename, options));
+        for row in rows {
+            w.write_record(row.into_iter().map(|cell| cell.to_string()))?;
+        }
+        w.into_inner().map_err(|e| e.into_error())?.finish()
+    }
+}
+
+impl TableDiffExtension for Provider {
+    fn default_table_options(&self) -> TableOptions {
+        TableOptions {
+            numeric_tolerance: self.settings.config.tolerance.absolute,
+            ..TableOptions::default()
+        }
+    }
+
+    #[track_caller]
+    fn csv_writer_with<S>(&self, filename: S, options: TableOptions) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        let compare_options = options.clone();
+        let diff_options = options.clone();
+        self.custom_test_with(
+            filename,
+            &options,
+            move |a, b| table_eq(a, b, &compare_options),
+            move |a, b, c, d| table_diff(a, b, c, d, &diff_options),
+        )
+    }
+}
+
+type Row = Vec<String>;
+
+enum RowChange<'a> {
+    Same(&'a Row),
+    Changed { actual: &'a Row, expected: &'a Row },
+    Inserted(&'a Row),
+    Deleted(&'a Row),
+}
+
+impl<'a> RowChange<'a> {
+    fn is_same(&self) -> bool {
+        matches!(self, RowChange::Same(_))
+    }
+}
+
+fn read_table<R: Read>(r: R) -> IoResult<Vec<Row>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(r);
+    reader
+        .records()
+        .map(|record| {
+            record
+                .map(|record| record.iter().map(String::from).collect())
+                .map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+        }).collect()
+}
+
+fn cells_eq(actual: &str, expected: &str, options: &TableOptions) -> bool {
+    if actual == expected {
+        return true;
+    }
+    match (actual.trim().parse::<f64>(), expected.trim().parse::<f64>()) {
+        (Ok(a), Ok(e)) => (a - e).abs() <= options.numeric_tolerance,
+        _ => false,
+    }
+}
+
+fn rows_eq(actual: &Row, expected: &Row, options: &TableOptions) -> bool {
+    actual.len() == expected.len()
+        && actual
+            .iter()
+            .zip(expected)
+            .all(|(a, e)| cells_eq(a, e, options))
+}
+
+fn row_key(row: &Row, options: &TableOptions) -> Vec<String> {
+    options
+        .key_columns
+        .iter()
+        .map(|&i| row.get(i).cloned().unwrap_or_default())
+        .collect()
+}
+
+fn compare_by_key<'a>(
+    actual: &'a [Row],
+    expected: &'a [Row],
+    options: &TableOptions,
+) -> Vec<RowChange<'a>> {
+    let mut by_key: HashMap<Vec<String>, VecDeque<usize>> = HashMap::new();
+    for (i, row) in expected.iter().enumerate() {
+        by_key.entry(row_key(row, options)).or_default().push_back(i);
+    }
+
+    let mut matched = vec![false; expected.len()];
+    let mut out = vec![];
+    for row in actual {
+        match by_key
+            .get_mut(&row_key(row, options))
+            .and_then(|rows| rows.pop_front())
+        {
+            Some(i) => {
+                matched[i] = true;
+                if rows_eq(row, &expected[i], options) {
+                    out.push(RowChange::Same(row));
+                } else {
+                    out.push(RowChange::Changed {
+                        actual: row,
+                        expected: &expected[i],
+                    });
+                }
+            }
+            None => out.push(RowChange::Inserted(row)),
+        }
+    }
+    for (row, matched) in expected.iter().zip(matched) {
+        if !matched {
+            out.push(RowChange::Deleted(row));
+        }
+    }
+    out
+}
+
+struct TolerantRow<'a> {
+    row: &'a Row,
+    options: &'a TableOptions,
+}
+
+impl<'a> PartialEq for TolerantRow<'a> {
+    fn eq(&self, other: &TolerantRow<'a>) -> bool {
+        rows_eq(self.row, other.row, self.options)
+    }
+}
+
+fn compare_by_position<'a>(
+    actual: &'a [Row],
+    expected: &'a [Row],
+    options: &'a TableOptions,
+) -> Vec<RowChange<'a>> {
+    let wrap = |rows: &'a [Row]| -> Vec<TolerantRow<'a>> {
+        rows.iter().map(|row| TolerantRow { row, options }).collect()
+    };
+    let actual = wrap(actual);
+    let expected = wrap(expected);
+
+    let mut out = vec![];
+    let mut inserted = vec![];
+    let mut deleted = vec![];
+
+    // A run of deletions and insertions between two unchanged rows is paired
+    // up into changed rows so that the diff can point at individual cells.
+    let flush = |out: &mut Vec<RowChange<'a>>, inserted: &mut Vec<&'a Row>, deleted: &mut Vec<&'a Row>| {
+        let paired = inserted.len().min(deleted.len());
+        for (a, e) in inserted.iter().zip(deleted.iter()) {
+            out.push(RowChange::Changed {
+                actual: a,
+                expected: e,
+            });
+        }
+        out.extend(deleted.drain(..).skip(paired).map(RowChange::Deleted));
+        out.extend(inserted.drain(..).skip(paired).map(RowChange::Inserted));
+    };
+
+    for change in diff::slice(&actual, &expected) {
+        match change {
+            diff::Result::Left(a) => inserted.push(a.row),
+            diff::Result::Right(e) => deleted.push(e.row),
+            diff::Result::Both(a, _) => {
+                flush(&mut out, &mut inserted, &mut deleted);
+                out.push(RowChange::Same(a.row));
+            }
+        }
+    }
+    flush(&mut out, &mut inserted, &mut deleted);
+    out
+}
+
+fn compare<'a>(
+    actual: &'a [Row],
+    expected: &'a [Row],
+    options: &'a TableOptions,
+) -> Vec<RowChange<'a>> {
+    if options.key_columns.is_empty() {
+        compare_by_position(actual, expected, options)
+    } else {
+        compare_by_key(actual, expected, options)
+    }
+}
+
+fn table_eq<R1: Read, R2: Read>(r1: R1, r2: R2, options: &TableOptions) -> IoResult<bool> {
+    let actual = read_table(r1)?;
+    let expected = read_table(r2)?;
+    Ok(compare(&actual, &expected, options)
+        .iter()
+        .all(RowChange::is_same))
+}
+
+fn render_row(actual: &Row, expected: &Row, options: &TableOptions) -> Row {
+    (0..actual.len().max(expected.len()))
+        .map(|i| match (actual.get(i), expected.get(i)) {
+            (Some(a), Some(e)) if cells_eq(a, e, options) => a.clone(),
+            (Some(a), Some(e)) => format!("[{} → {}]", e, a),
+            (Some(a), None) => format!("[ → {}]", a),
+            (None, Some(e)) => format!("[{} → ]", e),
+            (None, None) => unreachable!(),
+        }).collect()
+}
+
+fn table_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    options: &TableOptions,
+) -> IoResult<()> {
+    let actual = read_table(r1)?;
+    let expected = read_table(r2)?;
+
+    let lines: Vec<(char, Row)> = compare(&actual, &expected, options)
+        .into_iter()
+        .map(|change| match change {
+            RowChange::Same(row) => (' ', row.clone()),
+            RowChange::Changed { actual, expected } => {
+                ('~', render_row(actual, expected, options))
+            }
+            RowChange::Inserted(row) => ('+', row.clone()),
+            RowChange::Deleted(row) => ('-', row.clone()),
+        }).collect();
+
+    let mut widths: Vec<usize> = vec![];
+    for (_, row) in &lines {
+        for (i, cell) in row.iter().enumerate() {
+            let len = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(w) => *w = (*w).max(len),
+                None => widths.push(len),
+            }
+        }
+    }
+
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        writeln!(w, "# + inserted (actual only)")?;
+        writeln!(w, "# - deleted (expected only)")?;
+        writeln!(w, "# ~ changed, cells are marked as [expected → actual]")?;
+        for (marker, row) in &lines {
+            let mut line = marker.to_string();
+            for (cell, width) in row.iter().zip(&widths) {
+                line.push_str(&format!(" | {:width$}", cell, width = width));
+            }
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        Ok(())
+    })
+}
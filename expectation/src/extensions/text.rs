@@ -1,64 +1,125 @@
 use super::super::provider::{Provider, WriteRequester};
 use super::super::*;
+use super::add_extension;
 
 use std::fmt::Debug;
-use std::io::{Read, Result as IoResult, Write};
+use std::io::{BufRead, Read, Result as IoResult, Write};
 use std::path::Path;
 
 use diff;
 
+/// How far apart two numbers in a text file may be and still be considered
+/// equal.  A pair of numbers passes if it is within any one of the limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NumericTolerance {
+    pub absolute: f64,
+    /// Allowed difference as a fraction of the larger of the two magnitudes.
+    pub relative: f64,
+    /// Allowed distance in units in the last place.
+    pub ulps: u64,
+}
+
 pub trait TextDiffExtension {
+    /// Writes a text file that must match the expected file exactly, unless
+    /// `expectation.toml` sets a `tolerance`, in which case numbers are
+    /// compared with it as in `text_writer_with_tolerance`.  The configured
+    /// `redactions` are applied to each line as it is written.
+    #[track_caller]
     fn text_writer<N>(&self, filename: N) -> Writer
     where
         N: AsRef<Path>;
 
+    /// Like `text_writer`, but numbers in the file are compared using
+    /// `tolerance` while everything else must match exactly.
+    #[track_caller]
+    fn text_writer_with_tolerance<N>(&self, filename: N, tolerance: NumericTolerance) -> Writer
+    where
+        N: AsRef<Path>;
+
+    #[track_caller]
     fn text<N, S>(&self, filename: N, text: S) -> IoResult<()>
     where
         N: AsRef<Path>,
         S: AsRef<str>,
     {
         let mut w = self.text_writer(filename);
-        write!(w, "{}", text.as_ref())
+        write!(w, "{}", text.as_ref())?;
+        w.finish()
     }
 
+    #[track_caller]
     fn debug<N, D>(&self, filename: N, object: D) -> IoResult<()>
     where
         N: AsRef<Path>,
         D: Debug,
     {
         let mut w = self.text_writer(filename);
-        write!(w, "{:#?}", object)
+        write!(w, "{:#?}", object)?;
+        w.finish()
+    }
+
+    #[track_caller]
+    fn text_with_tolerance<N, S>(&self, filename: N, text: S, tolerance: NumericTolerance) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let mut w = self.text_writer_with_tolerance(filename, tolerance);
+        write!(w, "{}", text.as_ref())?;
+        w.finish()
+    }
+
+    #[track_caller]
+    fn debug_with_tolerance<N, D>(&self, filename: N, object: D, tolerance: NumericTolerance) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        D: Debug,
+    {
+        let mut w = self.text_writer_with_tolerance(filename, tolerance);
+        write!(w, "{:#?}", object)?;
+        w.finish()
     }
 }
 
 impl TextDiffExtension for Provider {
+    #[track_caller]
     fn text_writer<S>(&self, filename: S) -> Writer
     where
         S: AsRef<Path>,
     {
+        let configured = &self.settings.config.tolerance;
+        let tolerance = NumericTolerance {
+            absolute: configured.absolute,
+            relative: configured.relative,
+            ulps: configured.ulps,
+        };
+        if tolerance != NumericTolerance::default() {
+            return self.text_writer_with_tolerance(filename, tolerance);
+        }
+
         self.custom_test(
             filename,
             |a, b| text_eq(a, b),
             |a, b, c, d| text_diff(a, b, c, d),
-        )
+        ).redacted(&self.settings)
     }
-}
 
-fn text_eq<R1: Read, R2: Read>(mut r1: R1, mut r2: R2) -> IoResult<bool> {
-    let mut v1 = Vec::new();
-    let mut v2 = Vec::new();
-    r1.read_to_end(&mut v1)?;
-    r2.read_to_end(&mut v2)?;
-
-    Ok(v1 == v2)
+    #[track_caller]
+    fn text_writer_with_tolerance<S>(&self, filename: S, tolerance: NumericTolerance) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        self.custom_test_with(
+            filename,
+            &tolerance,
+            move |a, b| approx_text_eq(a, b, &tolerance),
+            move |a, b, c, d| approx_text_diff(a, b, c, d, &tolerance),
+        ).redacted(&self.settings)
+    }
 }
 
-fn add_extension(p: &Path, new_ext: &str) -> PathBuf {
-    let old_ext = match p.extension() {
-        Some(e) => e.to_string_lossy().into_owned(),
-        None => "".to_owned(),
-    };
-    p.with_extension(format!("{}{}", old_ext, new_ext))
+fn text_eq<R1: BufRead, R2: BufRead>(r1: R1, r2: R2) -> IoResult<bool> {
+    streams_eq(r1, r2)
 }
 
 fn text_diff<R1: Read, R2: Read>(
@@ -83,3 +144,218 @@ fn text_diff<R1: Read, R2: Read>(
         Ok(())
     })
 }
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Number(&'a str, f64),
+    Other(&'a str),
+}
+
+/// Returns the length in bytes of the number at the start of `s`, if any.
+fn number_len(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let digits = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+
+    let mut i = 0;
+    if i < bytes.len() && (bytes[i] == b'-' || bytes[i] == b'+') {
+        i += 1;
+    }
+    let int_end = digits(i);
+    let mut end = int_end;
+    if end < bytes.len() && bytes[end] == b'.' {
+        let frac_end = digits(end + 1);
+        if frac_end > end + 1 || int_end > i {
+            end = frac_end;
+        }
+    }
+    if end == i {
+        return None;
+    }
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exp = end + 1;
+        if exp < bytes.len() && (bytes[exp] == b'-' || bytes[exp] == b'+') {
+            exp += 1;
+        }
+        let exp_end = digits(exp);
+        if exp_end > exp {
+            end = exp_end;
+        }
+    }
+    Some(end)
+}
+
+/// Splits `s` into numbers and the text between them.  Digits that are part
+/// of an identifier (`x1`, `v2_final`) are not treated as numbers.
+fn tokenize(s: &str) -> Vec<Token<'_>> {
+    let mut out = vec![];
+    let mut other_start = 0;
+    let mut i = 0;
+    let mut prev: Option<char> = None;
+    while i < s.len() {
+        let starts_word = prev.is_none_or(|c| !(c.is_alphanumeric() || c == '_' || c == '.'));
+        if starts_word
+            && let Some(len) = number_len(&s[i..])
+            && let Ok(value) = s[i..i + len].parse::<f64>()
+        {
+            if other_start < i {
+                out.push(Token::Other(&s[other_start..i]));
+            }
+            out.push(Token::Number(&s[i..i + len], value));
+            i += len;
+            other_start = i;
+            prev = s[..i].chars().next_back();
+            continue;
+        }
+        let c = s[i..].chars().next().unwrap();
+        prev = Some(c);
+        i += c.len_utf8();
+    }
+    if other_start < s.len() {
+        out.push(Token::Other(&s[other_start..]));
+    }
+    out
+}
+
+fn ulp_distance(a: f64, b: f64) -> u64 {
+    let ordered = |x: f64| {
+        let bits = x.to_bits() as i64;
+        if bits < 0 { i64::MIN as i128 - bits as i128 } else { bits as i128 }
+    };
+    (ordered(a) - ordered(b)).unsigned_abs().min(u64::MAX as u128) as u64
+}
+
+fn numbers_eq(actual: f64, expected: f64, tolerance: &NumericTolerance) -> bool {
+    if actual == expected {
+        return true;
+    }
+    let difference = (actual - expected).abs();
+    difference <= tolerance.absolute
+        || difference <= tolerance.relative * actual.abs().max(expected.abs())
+        || ulp_distance(actual, expected) <= tolerance.ulps
+}
+
+fn tokens_eq(actual: &Token, expected: &Token, tolerance: &NumericTolerance) -> bool {
+    match (actual, expected) {
+        (Token::Number(a, x), Token::Number(e, y)) => a == e || numbers_eq(*x, *y, tolerance),
+        (Token::Other(a), Token::Other(e)) => a == e,
+        _ => false,
+    }
+}
+
+fn approx_lines_eq(actual: &str, expected: &str, tolerance: &NumericTolerance) -> bool {
+    let actual = tokenize(actual);
+    let expected = tokenize(expected);
+    actual.len() == expected.len()
+        && actual
+            .iter()
+            .zip(&expected)
+            .all(|(a, e)| tokens_eq(a, e, tolerance))
+}
+
+fn approx_text_eq<R1: Read, R2: Read>(
+    mut r1: R1,
+    mut r2: R2,
+    tolerance: &NumericTolerance,
+) -> IoResult<bool> {
+    let mut s1 = String::new();
+    let mut s2 = String::new();
+    r1.read_to_string(&mut s1)?;
+    r2.read_to_string(&mut s2)?;
+
+    Ok(approx_lines_eq(&s1, &s2, tolerance))
+}
+
+struct ApproxLine<'a> {
+    line: &'a str,
+    tolerance: &'a NumericTolerance,
+}
+
+impl<'a> PartialEq for ApproxLine<'a> {
+    fn eq(&self, other: &ApproxLine<'a>) -> bool {
+        approx_lines_eq(self.line, other.line, self.tolerance)
+    }
+}
+
+/// Builds a guide line with `^` under every number in `actual` that is out of
+/// tolerance, or `None` if the lines differ in more than their numbers.
+fn mark_numbers(actual: &str, expected: &str, tolerance: &NumericTolerance) -> Option<String> {
+    let actual = tokenize(actual);
+    let expected = tokenize(expected);
+    if actual.len() != expected.len() {
+        return None;
+    }
+    let mut marks = String::new();
+    for (a, e) in actual.iter().zip(&expected) {
+        match (a, e) {
+            (Token::Number(text, _), Token::Number(..)) => {
+                let mark = if tokens_eq(a, e, tolerance) { ' ' } else { '^' };
+                marks.extend(std::iter::repeat_n(mark, text.chars().count()));
+            }
+            (Token::Other(text), Token::Other(other)) if text == other => {
+                marks.extend(std::iter::repeat_n(' ', text.chars().count()));
+            }
+            _ => return None,
+        }
+    }
+    Some(marks.trim_end().to_owned())
+}
+
+fn approx_text_diff<R1: Read, R2: Read>(
+    mut r1: R1,
+    mut r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    tolerance: &NumericTolerance,
+) -> IoResult<()> {
+    let mut s1 = String::new();
+    let mut s2 = String::new();
+    r1.read_to_string(&mut s1)?;
+    r2.read_to_string(&mut s2)?;
+
+    let wrap = |s: &'_ str| -> Vec<String> { s.lines().map(String::from).collect() };
+    let (l1, l2) = (wrap(&s1), wrap(&s2));
+    let a: Vec<_> = l1.iter().map(|line| ApproxLine { line, tolerance }).collect();
+    let e: Vec<_> = l2.iter().map(|line| ApproxLine { line, tolerance }).collect();
+
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        let mut added: Vec<&str> = vec![];
+        let mut removed: Vec<&str> = vec![];
+
+        // A run of removed and added lines is paired up line by line so that
+        // the numbers that are out of tolerance can be pointed out.
+        let flush = |w: &mut dyn Write, added: &mut Vec<&str>, removed: &mut Vec<&str>| -> IoResult<()> {
+            let paired = added.len().min(removed.len());
+            for (a, e) in added.iter().zip(removed.iter()) {
+                writeln!(w, "+{}", a)?;
+                writeln!(w, "-{}", e)?;
+                if let Some(marks) = mark_numbers(a, e, tolerance) {
+                    writeln!(w, "?{}", marks)?;
+                }
+            }
+            for a in added.drain(..).skip(paired) {
+                writeln!(w, "+{}", a)?;
+            }
+            for e in removed.drain(..).skip(paired) {
+                writeln!(w, "-{}", e)?;
+            }
+            Ok(())
+        };
+
+        for diff in diff::slice(&a, &e) {
+            match diff {
+                diff::Result::Left(l) => added.push(l.line),
+                diff::Result::Right(r) => removed.push(r.line),
+                diff::Result::Both(l, _) => {
+                    flush(w, &mut added, &mut removed)?;
+                    writeln!(w, " {}", l.line)?;
+                }
+            }
+        }
+        flush(w, &mut added, &mut removed)
+    })
+}
@@ -0,0 +1,292 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::image::write_png;
+
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+
+use hound::{WavReader, WavWriter};
+use image::{Rgba, RgbaImage};
+
+pub use hound::{SampleFormat, WavSpec};
+
+pub trait WavDiffExtension {
+    /// The tolerance used when none is given, from the crate's
+    /// configuration.
+    fn default_audio_tolerance(&self) -> f32;
+
+    /// Samples that differ from the expected samples by no more than
+    /// `tolerance` (on a scale of -1.0 to 1.0) are considered equal.
+    #[track_caller]
+    fn wav_writer_with_tolerance<N>(&self, filename: N, tolerance: f32) -> Writer
+    where
+        N: AsRef<Path>;
+
+    #[track_caller]
+    fn wav_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>,
+    {
+        self.wav_writer_with_tolerance(filename, self.default_audio_tolerance())
+    }
+
+    #[track_caller]
+    fn wav<N>(&self, filename: N, spec: WavSpec, samples: &[f32]) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+    {
+        self.wav_with_tolerance(filename, spec, samples, self.default_audio_tolerance())
+    }
+
+    /// Writes interleaved `samples` in the range -1.0 to 1.0, converting
+    /// them to the sample format described by `spec`.
+    #[track_caller]
+    fn wav_with_tolerance<N>(
+        &self,
+        filename: N,
+        spec: WavSpec,
+        samples: &[f32],
+        tolerance: f32,
+    ) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+    {
+        let mut bytes = Cursor::new(vec![]);
+        {
+            let mut writer = WavWriter::new(&mut bytes, spec).map_err(to_io_error)?;
+            for &sample in samples {
+                match spec.sample_format {
+                    SampleFormat::Float => writer.write_sample(sample),
+                    SampleFormat::Int => {
+                        let max = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                        let scaled = (sample * max).round().clamp(-max, max - 1.0);
+                        writer.write_sample(scaled as i32)
+                    }
+                }.map_err(to_io_error)?;
+            }
+            writer.finalize().map_err(to_io_error)?;
+        }
+        let mut w = self.wav_writer_with_tolerance(filename, tolerance);
+        w.write_all(&bytes.into_inner())?;
+        w.finish()
+    }
+}
+
+impl WavDiffExtension for Provider {
+    fn default_audio_tolerance(&self) -> f32 {
+        self.settings.config.tolerance.audio
+    }
+
+    #[track_caller]
+    fn wav_writer_with_tolerance<S>(&self, filename: S, tolerance: f32) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        self.custom_test_with(
+            filename,
+            &tolerance,
+            move |a, b| wav_eq(a, b, tolerance),
+            move |a, b, c, d| wav_diff(a, b, c, d, tolerance),
+        )
+    }
+}
+
+fn to_io_error(e: hound::Error) -> IoError {
+    match e {
+        hound::Error::IoError(e) => e,
+        e => IoError::new(ErrorKind::InvalidData, e),
+    }
+}
+
+struct Audio {
+    spec: WavSpec,
+    samples: Vec<f32>,
+}
+
+impl Audio {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.spec.channels.max(1) as usize
+    }
+
+    fn sample(&self, frame: usize, channel: usize) -> Option<f32> {
+        self.samples
+            .get(frame * self.spec.channels as usize + channel)
+            .cloned()
+    }
+}
+
+/// Reads a WAV file and scales its samples to the range -1.0 to 1.0.
+fn read_audio<R: Read>(r: R) -> IoResult<Audio> {
+    let mut reader = WavReader::new(r).map_err(to_io_error)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(to_io_error)?,
+        SampleFormat::Int => {
+            let max = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / max))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(to_io_error)?
+        }
+    };
+    Ok(Audio { spec, samples })
+}
+
+fn same_layout(actual: &Audio, expected: &Audio) -> bool {
+    actual.spec.channels == expected.spec.channels
+        && actual.spec.sample_rate == expected.spec.sample_rate
+        && actual.samples.len() == expected.samples.len()
+}
+
+fn wav_eq<R1: Read, R2: Read>(r1: R1, r2: R2, tolerance: f32) -> IoResult<bool> {
+    let actual = read_audio(r1)?;
+    let expected = read_audio(r2)?;
+    Ok(same_layout(&actual, &expected)
+        && actual
+            .samples
+            .iter()
+            .zip(&expected.samples)
+            .all(|(a, e)| (a - e).abs() <= tolerance))
+}
+
+const WIDTH: u32 = 1024;
+const LANE_HEIGHT: u32 = 96;
+
+const BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const AXIS: Rgba<u8> = Rgba([200, 200, 200, 255]);
+const EXPECTED: Rgba<u8> = Rgba([90, 90, 255, 255]);
+const ACTUAL: Rgba<u8> = Rgba([0, 160, 0, 255]);
+const DIFFERENCE: Rgba<u8> = Rgba([230, 0, 0, 255]);
+
+fn draw_span(image: &mut RgbaImage, x: u32, top: u32, low: f32, high: f32, color: Rgba<u8>) {
+    let to_y = |v: f32| {
+        let v = v.clamp(-1.0, 1.0);
+        top + ((1.0 - v) / 2.0 * (LANE_HEIGHT - 1) as f32).round() as u32
+    };
+    for y in to_y(high)..=to_y(low) {
+        image.put_pixel(x, y, color);
+    }
+}
+
+/// Draws one pair of lanes per channel: the expected and actual waveforms
+/// on top of each other, and below them the difference between the two
+/// scaled so that the peak error fills the lane.
+fn render_waveform(actual: &Audio, expected: &Audio, peak_error: f32) -> RgbaImage {
+    let channels = actual.spec.channels.max(expected.spec.channels).max(1) as u32;
+    let frames = actual.frames().max(expected.frames()).max(1);
+    let mut image = RgbaImage::from_pixel(WIDTH, channels * LANE_HEIGHT * 2, BACKGROUND);
+
+    let range = |audio: &Audio, channel: usize, frames: &std::ops::Range<usize>| {
+        frames
+            .clone()
+            .filter_map(|f| audio.sample(f, channel))
+            .fold(None, |acc: Option<(f32, f32)>, s| match acc {
+                Some((lo, hi)) => Some((lo.min(s), hi.max(s))),
+                None => Some((s, s)),
+            })
+    };
+
+    for channel in 0..channels {
+        let wave_top = channel * LANE_HEIGHT * 2;
+        let diff_top = wave_top + LANE_HEIGHT;
+        for x in 0..WIDTH {
+            let start = x as usize * frames / WIDTH as usize;
+            let end = ((x as usize + 1) * frames / WIDTH as usize).max(start + 1);
+            let span = start..end;
+            let channel = channel as usize;
+
+            image.put_pixel(x, wave_top + LANE_HEIGHT / 2, AXIS);
+            image.put_pixel(x, diff_top + LANE_HEIGHT / 2, AXIS);
+            if let Some((lo, hi)) = range(expected, channel, &span) {
+                draw_span(&mut image, x, wave_top, lo, hi, EXPECTED);
+            }
+            if let Some((lo, hi)) = range(actual, channel, &span) {
+                draw_span(&mut image, x, wave_top, lo, hi, ACTUAL);
+            }
+
+            let error = span
+                .clone()
+                .filter_map(|f| match (actual.sample(f, channel), expected.sample(f, channel)) {
+                    (Some(a), Some(e)) => Some((a - e).abs()),
+                    (Some(s), None) | (None, Some(s)) => Some(s.abs()),
+                    (None, None) => None,
+                }).fold(0.0f32, f32::max);
+            if error > 0.0 && peak_error > 0.0 {
+                let scaled = error / peak_error;
+                draw_span(&mut image, x, diff_top, -scaled, scaled, DIFFERENCE);
+            }
+        }
+    }
+    image
+}
+
+fn wav_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    tolerance: f32,
+) -> IoResult<()> {
+    let actual = read_audio(r1)?;
+    let expected = read_audio(r2)?;
+
+    let compared = actual.samples.len().min(expected.samples.len());
+    let mut sum_of_squares = 0.0f64;
+    let mut peak_error = 0.0f32;
+    let mut peak_index = 0;
+    let mut out_of_tolerance = 0;
+    for (i, (a, e)) in actual.samples.iter().zip(&expected.samples).enumerate() {
+        let error = (a - e).abs();
+        sum_of_squares += (error as f64) * (error as f64);
+        if error > tolerance {
+            out_of_tolerance += 1;
+        }
+        if error > peak_error {
+            peak_error = error;
+            peak_index = i;
+        }
+    }
+    let rms_error = if compared == 0 {
+        0.0
+    } else {
+        (sum_of_squares / compared as f64).sqrt()
+    };
+
+    write_requester.request(path.join("summary.txt"), |w| {
+        for (label, audio) in &[("actual:  ", &actual), ("expected:", &expected)] {
+            writeln!(
+                w,
+                "{} {} channel(s), {} Hz, {} bit {:?}, {} frames",
+                label,
+                audio.spec.channels,
+                audio.spec.sample_rate,
+                audio.spec.bits_per_sample,
+                audio.spec.sample_format,
+                audio.frames()
+            )?;
+        }
+        if !same_layout(&actual, &expected) {
+            writeln!(w, "channel count, sample rate or length are different")?;
+        }
+        writeln!(w, "tolerance:         {}", tolerance)?;
+        writeln!(w, "samples compared:  {}", compared)?;
+        writeln!(w, "out of tolerance:  {}", out_of_tolerance)?;
+        writeln!(w, "rms error:         {:.6}", rms_error)?;
+        let channels = expected.spec.channels.max(1) as usize;
+        writeln!(
+            w,
+            "peak error:        {:.6} (frame {}, channel {})",
+            peak_error,
+            peak_index / channels,
+            peak_index % channels
+        )?;
+        Ok(())
+    })?;
+
+    let waveform = render_waveform(&actual, &expected, peak_error);
+    write_png(path.join("waveform.png"), &waveform, write_requester)
+}
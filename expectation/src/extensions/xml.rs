@@ -0,0 +1,595 @@
+use super::super::provider::{Provider, WriteRequester};
+use super::super::*;
+use super::add_extension;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+
+use diff;
+use roxmltree;
+
+pub trait XmlDiffExtension {
+    #[track_caller]
+    fn xml_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>;
+
+    #[track_caller]
+    fn html_writer<N>(&self, filename: N) -> Writer
+    where
+        N: AsRef<Path>;
+
+    #[track_caller]
+    fn xml<N, S>(&self, filename: N, xml: S) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let mut w = self.xml_writer(filename);
+        write!(w, "{}", xml.as_ref())?;
+        w.finish()
+    }
+
+    #[track_caller]
+    fn html<N, S>(&self, filename: N, html: S) -> IoResult<()>
+    where
+        N: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let mut w = self.html_writer(filename);
+        write!(w, "{}", html.as_ref())?;
+        w.finish()
+    }
+}
+
+impl XmlDiffExtension for Provider {
+    #[track_caller]
+    fn xml_writer<S>(&self, filename: S) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        self.custom_test(
+            filename,
+            |a, b| tree_eq(a, b, parse_xml),
+            |a, b, c, d| tree_diff(a, b, c, d, parse_xml),
+        )
+    }
+
+    #[track_caller]
+    fn html_writer<S>(&self, filename: S) -> Writer
+    where
+        S: AsRef<Path>,
+    {
+        self.custom_test(
+            filename,
+            |a, b| tree_eq(a, b, parse_html),
+            |a, b, c, d| tree_diff(a, b, c, d, parse_html),
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Element {
+    name: String,
+    attributes: BTreeMap<String, String>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn new(name: String) -> Element {
+        Element {
+            name,
+            attributes: BTreeMap::new(),
+            children: vec![],
+        }
+    }
+}
+
+/// Joins runs of whitespace into a single space so that reflowing text does
+/// not count as a change.
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn push_text(children: &mut Vec<Node>, text: &str, preserve: bool) {
+    let text = if preserve {
+        text.to_owned()
+    } else {
+        collapse_whitespace(text)
+    };
+    if !text.trim().is_empty() {
+        children.push(Node::Text(text));
+    }
+}
+
+fn xml_name(name: roxmltree::ExpandedName) -> String {
+    match name.namespace() {
+        Some(ns) => format!("{{{}}}{}", ns, name.name()),
+        None => name.name().to_owned(),
+    }
+}
+
+fn convert_xml(node: roxmltree::Node) -> Element {
+    let mut element = Element::new(xml_name(node.tag_name()));
+    for attribute in node.attributes() {
+        let name = match attribute.namespace() {
+            Some(ns) => format!("{{{}}}{}", ns, attribute.name()),
+            None => attribute.name().to_owned(),
+        };
+        element.attributes.insert(name, attribute.value().to_owned());
+    }
+    for child in node.children() {
+        if child.is_element() {
+            element.children.push(Node::Element(convert_xml(child)));
+        } else if child.is_text() {
+            push_text(&mut element.children, child.text().unwrap_or(""), false);
+        }
+    }
+    element
+}
+
+fn parse_xml(s: &str) -> IoResult<Vec<Node>> {
+    let document =
+        roxmltree::Document::parse(s).map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+    Ok(vec![Node::Element(convert_xml(document.root_element()))])
+}
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source",
+    "track", "wbr",
+];
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+const PREFORMATTED_ELEMENTS: &[&str] = &["pre", "textarea"];
+
+fn decode_entities(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end < 12 => end,
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses the attributes of a start tag, returning them along with whether
+/// the tag was self-closing.
+fn parse_attributes(s: &str) -> (BTreeMap<String, String>, bool) {
+    let mut attributes = BTreeMap::new();
+    let self_closing = s.trim_end().ends_with('/');
+    let mut rest = s.trim_end().trim_end_matches('/');
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let value = if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            match after_eq.chars().next() {
+                Some(quote @ '"') | Some(quote @ '\'') => {
+                    let end = after_eq[1..].find(quote).map(|i| i + 1).unwrap_or(after_eq.len());
+                    rest = after_eq.get(end + 1..).unwrap_or("");
+                    &after_eq[1..end]
+                }
+                _ => {
+                    let end = after_eq.find(char::is_whitespace).unwrap_or(after_eq.len());
+                    rest = &after_eq[end..];
+                    &after_eq[..end]
+                }
+            }
+        } else {
+            ""
+        };
+        if !name.is_empty() {
+            attributes.insert(name, decode_entities(value));
+        }
+    }
+    (attributes, self_closing)
+}
+
+/// Where the next tag, comment or declaration in `s` starts.  A `<` that
+/// isn't followed by one, like in `1 < 2`, is text.
+fn markup_start(s: &str) -> Option<usize> {
+    s.match_indices('<')
+        .map(|(i, _)| i)
+        .find(|&i| matches!(s[i + 1..].chars().next(), Some(c) if c.is_ascii_alphabetic() || "/!?".contains(c)))
+}
+
+/// A forgiving HTML parser: unclosed elements are closed by their parent's
+/// end tag and stray end tags are ignored.
+fn parse_html(s: &str) -> IoResult<Vec<Node>> {
+    let mut stack = vec![Element::new(String::new())];
+    let mut rest = s;
+
+    fn close(stack: &mut Vec<Element>) {
+        let element = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(Node::Element(element));
+    }
+    fn preserve(stack: &[Element]) -> bool {
+        stack
+            .iter()
+            .any(|e| PREFORMATTED_ELEMENTS.contains(&e.name.as_str()))
+    }
+
+    while !rest.is_empty() {
+        let text_end = markup_start(rest).unwrap_or(rest.len());
+        if text_end > 0 {
+            let text = decode_entities(&rest[..text_end]);
+            let keep = preserve(&stack);
+            push_text(&mut stack.last_mut().unwrap().children, &text, keep);
+            rest = &rest[text_end..];
+            continue;
+        }
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map(|i| &comment[i + 3..]).unwrap_or("");
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map(|i| &rest[i + 1..]).unwrap_or("");
+        } else if let Some(end_tag) = rest.strip_prefix("</") {
+            let close_at = end_tag.find('>').unwrap_or(end_tag.len());
+            let name = end_tag[..close_at].trim().to_lowercase();
+            rest = end_tag.get(close_at + 1..).unwrap_or("");
+            if let Some(depth) = stack.iter().rposition(|e| e.name == name)
+                && depth > 0
+            {
+                while stack.len() > depth {
+                    close(&mut stack);
+                }
+            }
+        } else {
+            let tag = &rest[1..];
+            let close_at = tag.find('>').unwrap_or(tag.len());
+            let contents = &tag[..close_at];
+
+            let name_end = contents
+                .find(|c: char| c.is_whitespace() || c == '/')
+                .unwrap_or(contents.len());
+            let name = contents[..name_end].to_lowercase();
+            if name.is_empty() {
+                // Not a tag after all, so the `<` is text.
+                push_text(&mut stack.last_mut().unwrap().children, "<", false);
+                rest = tag;
+                continue;
+            }
+            rest = tag.get(close_at + 1..).unwrap_or("");
+            let (attributes, self_closing) = parse_attributes(&contents[name_end..]);
+            let mut element = Element::new(name);
+            element.attributes = attributes;
+
+            if self_closing || VOID_ELEMENTS.contains(&element.name.as_str()) {
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .children
+                    .push(Node::Element(element));
+            } else if RAW_TEXT_ELEMENTS.contains(&element.name.as_str()) {
+                let end_tag = format!("</{}", element.name);
+                let end = rest.to_ascii_lowercase().find(&end_tag).unwrap_or(rest.len());
+                let text = &rest[..end];
+                let text = if element.name == "title" {
+                    decode_entities(text)
+                } else {
+                    text.to_owned()
+                };
+                push_text(&mut element.children, text.trim(), true);
+                rest = &rest[end..];
+                rest = rest.find('>').map(|i| &rest[i + 1..]).unwrap_or("");
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .children
+                    .push(Node::Element(element));
+            } else {
+                stack.push(element);
+            }
+        }
+    }
+
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    Ok(stack.pop().unwrap().children)
+}
+
+fn escape(s: &str, quote: bool) -> String {
+    let s = s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    if quote { s.replace('"', "&quot;") } else { s }
+}
+
+fn pretty_print(out: &mut String, nodes: &[Node], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for node in nodes {
+        match node {
+            Node::Text(text) => {
+                for line in text.lines() {
+                    out.push_str(&format!("{}{}\n", indent, escape(line, false)));
+                }
+            }
+            Node::Element(element) => {
+                out.push_str(&format!("{}<{}", indent, element.name));
+                for (name, value) in &element.attributes {
+                    out.push_str(&format!(" {}=\"{}\"", name, escape(value, true)));
+                }
+                if element.children.is_empty() {
+                    out.push_str("/>\n");
+                } else {
+                    out.push_str(">\n");
+                    pretty_print(out, &element.children, depth + 1);
+                    out.push_str(&format!("{}</{}>\n", indent, element.name));
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum NodeKey<'a> {
+    Element(&'a str),
+    Text,
+}
+
+fn key(node: &Node) -> NodeKey<'_> {
+    match node {
+        Node::Element(e) => NodeKey::Element(&e.name),
+        Node::Text(_) => NodeKey::Text,
+    }
+}
+
+/// Gives each node its XPath-like step (`div[2]`, `text()`) among its
+/// siblings.  Indices are only added when a name occurs more than once.
+fn steps(nodes: &[Node]) -> Vec<String> {
+    let mut totals: HashMap<String, usize> = HashMap::new();
+    let names: Vec<String> = nodes
+        .iter()
+        .map(|n| match n {
+            Node::Element(e) => e.name.clone(),
+            Node::Text(_) => "text()".to_owned(),
+        }).collect();
+    for name in &names {
+        *totals.entry(name.clone()).or_default() += 1;
+    }
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    names
+        .into_iter()
+        .map(|name| {
+            let index = seen.entry(name.clone()).or_default();
+            *index += 1;
+            if totals[&name] > 1 {
+                format!("{}[{}]", name, index)
+            } else {
+                name
+            }
+        }).collect()
+}
+
+fn describe(node: &Node) -> String {
+    match node {
+        Node::Text(text) => format!("{:?}", text),
+        Node::Element(element) => {
+            let mut out = String::new();
+            pretty_print(&mut out, std::slice::from_ref(node), 0);
+            match out.lines().count() {
+                1 => out.trim_end().to_owned(),
+                _ => format!("<{}> ...", element.name),
+            }
+        }
+    }
+}
+
+fn tree_changes(actual: &[Node], expected: &[Node], path: &str, out: &mut Vec<String>) {
+    let actual_steps = steps(actual);
+    let expected_steps = steps(expected);
+    let actual_keys: Vec<_> = actual.iter().map(key).collect();
+    let expected_keys: Vec<_> = expected.iter().map(key).collect();
+
+    let (mut ai, mut ei) = (0, 0);
+    for change in diff::slice(&actual_keys, &expected_keys) {
+        match change {
+            diff::Result::Left(_) => {
+                out.push(format!("+ {}/{}: {}", path, actual_steps[ai], describe(&actual[ai])));
+                ai += 1;
+            }
+            diff::Result::Right(_) => {
+                out.push(format!("- {}/{}: {}", path, expected_steps[ei], describe(&expected[ei])));
+                ei += 1;
+            }
+            diff::Result::Both(_, _) => {
+                let path = format!("{}/{}", path, expected_steps[ei]);
+                match (&actual[ai], &expected[ei]) {
+                    (Node::Text(a), Node::Text(e)) if a != e => {
+                        out.push(format!("~ {}: {:?} → {:?}", path, e, a));
+                    }
+                    (Node::Element(a), Node::Element(e)) => element_changes(a, e, &path, out),
+                    _ => {}
+                }
+                ai += 1;
+                ei += 1;
+            }
+        }
+    }
+}
+
+fn element_changes(actual: &Element, expected: &Element, path: &str, out: &mut Vec<String>) {
+    let names: BTreeSet<&String> = actual
+        .attributes
+        .keys()
+        .chain(expected.attributes.keys())
+        .collect();
+    for name in names {
+        match (actual.attributes.get(name), expected.attributes.get(name)) {
+            (Some(a), None) => out.push(format!("+ {}/@{}: {:?}", path, name, a)),
+            (None, Some(e)) => out.push(format!("- {}/@{}: {:?}", path, name, e)),
+            (Some(a), Some(e)) if a != e => {
+                out.push(format!("~ {}/@{}: {:?} → {:?}", path, name, e, a))
+            }
+            _ => {}
+        }
+    }
+    tree_changes(&actual.children, &expected.children, path, out);
+}
+
+fn read_trees<R1: Read, R2: Read>(
+    mut r1: R1,
+    mut r2: R2,
+    parse: fn(&str) -> IoResult<Vec<Node>>,
+) -> IoResult<(Vec<Node>, Vec<Node>)> {
+    let mut s1 = String::new();
+    let mut s2 = String::new();
+    r1.read_to_string(&mut s1)?;
+    r2.read_to_string(&mut s2)?;
+    Ok((parse(&s1)?, parse(&s2)?))
+}
+
+fn tree_eq<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    parse: fn(&str) -> IoResult<Vec<Node>>,
+) -> IoResult<bool> {
+    let (actual, expected) = read_trees(r1, r2, parse)?;
+    Ok(actual == expected)
+}
+
+const CONTEXT: usize = 3;
+
+/// Writes a unified diff of `expected` against `actual` with `CONTEXT`
+/// lines of context around every hunk.
+fn write_unified_diff(w: &mut dyn Write, actual: &str, expected: &str) -> IoResult<()> {
+    let lines = diff::lines(actual.trim_end_matches('\n'), expected.trim_end_matches('\n'));
+    let changed: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| !matches!(l, diff::Result::Both(_, _)))
+        .map(|(i, _)| i)
+        .collect();
+
+    writeln!(w, "--- expected")?;
+    writeln!(w, "+++ actual")?;
+
+    // Group the changed lines into hunks that overlap once context is added.
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for i in changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let (mut expected_line, mut actual_line, mut pos) = (1, 1, 0);
+    for (start, end) in hunks {
+        for line in &lines[pos..start] {
+            if let diff::Result::Both(_, _) = line {
+                expected_line += 1;
+                actual_line += 1;
+            }
+        }
+        let hunk = &lines[start..end];
+        let expected_len = hunk
+            .iter()
+            .filter(|l| !matches!(l, diff::Result::Left(_)))
+            .count();
+        let actual_len = hunk
+            .iter()
+            .filter(|l| !matches!(l, diff::Result::Right(_)))
+            .count();
+        writeln!(
+            w,
+            "@@ -{},{} +{},{} @@",
+            expected_line, expected_len, actual_line, actual_len
+        )?;
+        // Removed lines are listed before the added lines that replace them.
+        let mut added = vec![];
+        for line in hunk {
+            match line {
+                diff::Result::Left(l) => added.push(*l),
+                diff::Result::Right(r) => writeln!(w, "-{}", r)?,
+                diff::Result::Both(l, _) => {
+                    for a in added.drain(..) {
+                        writeln!(w, "+{}", a)?;
+                    }
+                    writeln!(w, " {}", l)?;
+                }
+            }
+        }
+        for a in added {
+            writeln!(w, "+{}", a)?;
+        }
+        expected_line += expected_len;
+        actual_line += actual_len;
+        pos = end;
+    }
+    Ok(())
+}
+
+fn tree_diff<R1: Read, R2: Read>(
+    r1: R1,
+    r2: R2,
+    path: &Path,
+    write_requester: &mut WriteRequester,
+    parse: fn(&str) -> IoResult<Vec<Node>>,
+) -> IoResult<()> {
+    let (actual, expected) = read_trees(r1, r2, parse)?;
+
+    let mut changes = vec![];
+    tree_changes(&actual, &expected, "", &mut changes);
+    write_requester.request(add_extension(path, ".tree.diff"), |w| {
+        for change in &changes {
+            writeln!(w, "{}", change)?;
+        }
+        Ok(())
+    })?;
+
+    let mut actual_pretty = String::new();
+    let mut expected_pretty = String::new();
+    pretty_print(&mut actual_pretty, &actual, 0);
+    pretty_print(&mut expected_pretty, &expected, 0);
+    write_requester.request(add_extension(path, ".diff"), |w| {
+        write_unified_diff(w, &actual_pretty, &expected_pretty)
+    })
+}
@@ -1,7 +1,7 @@
 use expectation_shared::Result as EResult;
-use serde_json;
 use std::env;
 use std::net::TcpStream;
+use std::path::Path;
 
 fn get_stream() -> Option<TcpStream> {
     let env_var = match env::var("CARGO_EXPECT_IPC") {
@@ -21,8 +21,8 @@ fn get_stream() -> Option<TcpStream> {
     Some(stream)
 }
 
-pub fn send(test_name: &str, results: &Vec<EResult>) {
+pub fn send(test_name: &str, root: &Path, results: &Vec<EResult>) {
     if let Some(mut s) = get_stream() {
-        serde_json::to_writer_pretty(&mut s, &(test_name, results)).unwrap();
+        serde_json::to_writer_pretty(&mut s, &(test_name, root, results)).unwrap();
     }
 }
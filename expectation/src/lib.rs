@@ -1,5 +1,6 @@
 extern crate expectation_shared;
 extern crate serde_json;
+extern crate regex;
 
 #[cfg(feature = "text")]
 extern crate diff;
@@ -7,18 +8,38 @@ extern crate diff;
 #[cfg(feature = "image")]
 extern crate image;
 
+#[cfg(feature = "table")]
+extern crate csv;
+
+#[cfg(feature = "svg")]
+extern crate resvg;
+#[cfg(any(feature = "svg", feature = "xml"))]
+extern crate roxmltree;
+
+#[cfg(feature = "wav")]
+extern crate hound;
+
+mod executor;
 pub mod extensions;
 mod ipc;
 mod provider;
+mod settings;
 #[cfg(test)]
 mod test;
 
+pub use executor::block_on;
 pub use provider::Provider;
 
+use expectation_shared::config::Config;
 use expectation_shared::filesystem::*;
+use expectation_shared::index::{self, Index};
+use expectation_shared::history::History;
+use expectation_shared::promote::promote_results;
 use expectation_shared::{Result as EResult, ResultKind};
 use std::collections::HashSet;
+use std::io::{BufRead, Result as IoResult};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 pub use provider::Writer;
 
@@ -29,40 +50,281 @@ fn should_continue(name: &str) -> bool {
     }
 }
 
-fn file_filter(file: &Path) -> bool {
-    match std::env::var("CARGO_EXPECT_FILES") {
-        Ok(v) => v
-            .split(",")
-            .any(|ending| file.to_str().map(|f| f.ends_with(ending)).unwrap_or(false)),
-        Err(_) => true,
+/// Compares the files asked for with `--filetypes`, or by the config.
+fn file_filter(config: &Config) -> impl Fn(&Path) -> bool + '_ {
+    let filetypes: Option<Vec<String>> = std::env::var("CARGO_EXPECT_FILES")
+        .ok()
+        .map(|v| v.split(",").map(String::from).collect());
+    move |file| config.accepts(file, filetypes.as_deref())
+}
+
+fn use_index() -> bool {
+    std::env::var("CARGO_EXPECT_INDEX").is_ok()
+}
+
+/// In CI, nothing is written to the snapshot directory: actual files and
+/// diffs go to a temporary directory, and missing expected files are errors
+/// that have to be fixed by blessing the snapshots locally.
+fn ci_mode() -> bool {
+    matches!(std::env::var("EXPECTATION_CI").as_deref(), Ok(v) if !v.is_empty() && v != "0")
+}
+
+/// Where the actual files and diffs of a test are written in CI mode.  Each
+/// test has its own directory, which is removed again if the test passes.
+fn ci_output_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("expectation-ci-{}-{}", std::process::id(), name))
+}
+
+/// Which snapshots `expect` blesses by itself, as set by `EXPECTATION_UPDATE`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UpdateMode {
+    /// Write new and changed files to `expected/` and remove stale ones.
+    Always,
+    /// Only write files that have no expected file yet.
+    New,
+    No,
+}
+
+impl UpdateMode {
+    fn from_env() -> UpdateMode {
+        match std::env::var("EXPECTATION_UPDATE").as_deref() {
+            Err(_) | Ok("") | Ok("no") | Ok("0") => UpdateMode::No,
+            Ok("always") | Ok("1") => UpdateMode::Always,
+            Ok("new") => UpdateMode::New,
+            Ok(other) => panic!(
+                "EXPECTATION_UPDATE must be \"always\", \"new\" or \"no\", not {:?}",
+                other
+            ),
+        }
+    }
+
+    fn applies_to(self, kind: &ResultKind) -> bool {
+        matches!(
+            (self, kind),
+            (UpdateMode::Always, ResultKind::ExpectedNotFound(_))
+                | (UpdateMode::Always, ResultKind::ActualNotFound(_))
+                | (UpdateMode::Always, ResultKind::Difference(_))
+                | (UpdateMode::New, ResultKind::ExpectedNotFound(_))
+        )
+    }
+}
+
+/// Identifies the `cargo test` invocation that this process is part of, so
+/// that all of its tests record their promotions in one history batch.  The
+/// test binaries of one invocation share their parent process, or
+/// `EXPECTATION_RUN_ID` can be set to group several invocations.
+fn run_id() -> String {
+    if let Ok(run) = std::env::var("EXPECTATION_RUN_ID") {
+        return run;
+    }
+    #[cfg(unix)]
+    return format!("parent-{}", std::os::unix::process::parent_id());
+    #[cfg(not(unix))]
+    return format!("process-{}", std::process::id());
+}
+
+/// Promotes the results that `mode` covers, in the same way as `cargo expect
+/// promote`, and reports them as passing.  The files that are replaced are
+/// recorded in the history batch of this run, so `cargo expect undo` puts
+/// back what the whole run changed.
+fn update(mode: UpdateMode, results: Vec<EResult>) -> Vec<EResult> {
+    let fs = RealFileSystem { root: "/".into() };
+    let (pending, mut out): (Vec<_>, Vec<_>) =
+        results.into_iter().partition(|r| mode.applies_to(&r.kind));
+
+    let mut history = History::for_run(run_id());
+    let promoted = {
+        // Other tests in this process may be numbering a history batch or
+        // saving the index at the same time.
+        let _lock = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        promote_results(pending, &fs, &mut history)
+    };
+    for (_, batch) in history.recorded() {
+        println!("Recorded as batch {}; `cargo expect undo` reverts it", batch);
+    }
+
+    for (result, promoted) in promoted {
+        out.push(match promoted {
+            Ok(detail) => {
+                println!("Updated {}: {}", result.file_name.to_string_lossy(), detail);
+                EResult { kind: ResultKind::Ok, ..result }
+            }
+            Err(e) => EResult { kind: ResultKind::IoError(format!("{:?}", e)), ..result },
+        });
+    }
+    out
+}
+
+/// Held while the index or the history is loaded, changed and saved, so that
+/// tests running in parallel don't overwrite each other's changes.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// Compares two streams a buffer at a time, stopping at the first difference
+/// instead of reading both of them into memory.
+pub fn streams_eq<R1: BufRead, R2: BufRead>(mut r1: R1, mut r2: R2) -> IoResult<bool> {
+    loop {
+        let b1 = r1.fill_buf()?;
+        let b2 = r2.fill_buf()?;
+        if b1.is_empty() || b2.is_empty() {
+            return Ok(b1.is_empty() && b2.is_empty());
+        }
+        let len = b1.len().min(b2.len());
+        if b1[..len] != b2[..len] {
+            return Ok(false);
+        }
+        r1.consume(len);
+        r2.consume(len);
     }
 }
 
 pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
+    expect_in(&default_crate_dir(), None, name, f)
+}
+
+/// Like `expect`, but for test bodies that return a future.  The future is
+/// awaited on whatever executor drives the returned future, so it is free to
+/// spawn tasks that use the provider.
+pub async fn expect_async<F, Fut>(name: &str, f: F)
+where
+    F: FnOnce(Provider) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    expect_async_in(&default_crate_dir(), None, name, f).await
+}
+
+/// Like `expect`, but with the snapshots of the crate in `crate_dir`.
+/// `root` overrides the snapshot directory from the config, and is itself
+/// overridden by the `EXPECTATION_ROOT` environment variable.  Relative
+/// roots are relative to `crate_dir`.
+///
+/// `#[expectation_test]` calls this with the `CARGO_MANIFEST_DIR` of the
+/// crate that the test is in.
+pub fn expect_in<F: FnOnce(Provider)>(crate_dir: &str, root: Option<&str>, name: &str, f: F) {
+    if let Some(run) = start(crate_dir, root, name) {
+        f(run.provider.clone());
+        finish(run);
+    }
+}
+
+/// Like `expect_in`, but for test bodies that return a future.
+pub async fn expect_async_in<F, Fut>(crate_dir: &str, root: Option<&str>, name: &str, f: F)
+where
+    F: FnOnce(Provider) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    if let Some(run) = start(crate_dir, root, name) {
+        f(run.provider.clone()).await;
+        finish(run);
+    }
+}
+
+/// The crate being tested, when the test didn't say: cargo sets
+/// `CARGO_MANIFEST_DIR` when it runs tests, but other runners might not.
+fn default_crate_dir() -> String {
+    std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".into())
+}
+
+/// A test that is being run.
+struct Run<'a> {
+    name: &'a str,
+    /// Holds the `expected` directory.
+    root: PathBuf,
+    /// Holds the `actual` and `diff` directories; the same as `root` unless
+    /// running in CI.
+    output: PathBuf,
+    provider: Provider,
+}
+
+/// Checks the test name and builds the provider for the test, or returns
+/// `None` if the test is filtered out.
+fn start<'a>(crate_dir: &str, root: Option<&str>, name: &'a str) -> Option<Run<'a>> {
     if !name.starts_with("expectation_test_") {
         panic!("expectation test {} is an invalid test name.  It must start with \"expectation_test_\"", name);
     }
 
     let name = name.trim_start_matches("expectation_test_");
     if !should_continue(name) {
-        return;
+        return None;
     }
 
-    let top_fs = RealFileSystem {
-        root: Path::new("./").canonicalize().unwrap(),
-    }.subsystem(Path::new("expectation-tests"));
-    let act_fs = top_fs
-        .subsystem(Path::new("actual"))
-        .subsystem(Path::new(name));
-    let provider = Provider::new(top_fs.duplicate(), act_fs.duplicate());
-    f(provider.clone());
+    let crate_dir = Path::new(crate_dir).canonicalize().unwrap();
+    let settings = Config::load(&crate_dir)
+        .map(|config| match root {
+            Some(root) => Config { root: root.into(), ..config },
+            None => config,
+        }).and_then(settings::Settings::new)
+        .unwrap_or_else(|e| panic!("invalid expectation configuration: {}", e));
+    let root = settings.config.snapshot_root(&crate_dir);
+    let output = if ci_mode() { ci_output_dir(name) } else { root.clone() };
+    let out_fs = RealFileSystem { root: output.clone() };
+    let act_fs = out_fs.subsystem(Path::new("actual")).subsystem(Path::new(name));
+    clear(&*act_fs);
+    clear(&*out_fs.subsystem(Path::new("diff")).subsystem(Path::new(name)));
+    let provider = Provider::new(RealFileSystem { root: root.clone() }.duplicate(), act_fs)
+        .with_settings(Arc::new(settings));
+    Some(Run { name, root, output, provider })
+}
+
+/// Removes what an earlier run of the test left behind, so that stale files
+/// are neither compared nor promoted.
+fn clear(fs: &dyn FileSystem) {
+    for file in fs.files() {
+        if let Err(e) = fs.remove(&file) {
+            panic!("couldn't remove {}: {}", fs.full_path_for(&file).to_string_lossy(), e);
+        }
+    }
+}
 
+fn finish(run: Run) {
+    let Run { name, root, output, provider } = run;
+    let ci = ci_mode();
+    let top_fs = RealFileSystem { root: root.clone() }.duplicate();
+    let out_fs = RealFileSystem { root: output.clone() }.duplicate();
     let mut succeeded = true;
-    let results = validate(name, top_fs, provider, file_filter);
+    let settings = provider.settings.clone();
+    let file_filter = file_filter(&settings.config);
+    let results = if use_index() && !ci {
+        let mut index = Index::load(&*top_fs);
+        let results = validate_indexed(
+            name,
+            top_fs.duplicate(),
+            out_fs,
+            provider,
+            file_filter,
+            Some(&mut index),
+        );
 
-    ipc::send(name, &results);
+        // Tests run in parallel, so only this test's changes are written back
+        // on top of whatever the index holds now.
+        let _lock = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut latest = Index::load(&*top_fs);
+        latest.merge(index);
+        if let Err(e) = latest.save(&*top_fs) {
+            println!("Failed to save the expectation index: {}", e);
+        }
+        results
+    } else {
+        validate_indexed(name, top_fs, out_fs, provider, file_filter, None)
+    };
+
+    let results = match UpdateMode::from_env() {
+        UpdateMode::No => results,
+        _ if ci => panic!("EXPECTATION_UPDATE can't be used together with EXPECTATION_CI"),
+        mode => update(mode, results),
+    };
+    let needs_blessing = results
+        .iter()
+        .filter(|r| {
+            matches!(
+                r.kind,
+                ResultKind::ExpectedNotFound(_) | ResultKind::ActualNotFound(_) | ResultKind::Difference(_)
+            )
+        }).count();
+
+    ipc::send(name, &root, &results);
 
     for result in results {
+        let show_location = !matches!(result.kind, ResultKind::Ok | ResultKind::DuplicateSnapshot(_));
         match result.kind {
             ResultKind::Ok => {}
             ResultKind::ActualNotFound(double) => {
@@ -96,7 +358,40 @@ pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
                 }
                 succeeded = false;
             }
-            _ => {}
+            ResultKind::IoError(error) => {
+                println!("Io error");
+                println!("  file   {}", result.file_name.to_string_lossy());
+                println!("  error  {}", error);
+                succeeded = false;
+            }
+            ResultKind::UnexpectedFile(actual) => {
+                println!("Unexpected file, not requested from the provider");
+                println!("  actual  {}", actual.to_string_lossy());
+                succeeded = false;
+            }
+            ResultKind::DuplicateSnapshot(duplicate) => {
+                println!("Snapshot written more than once");
+                println!("  file    {}", result.file_name.to_string_lossy());
+                println!("  first   {}", duplicate.first);
+                println!("  second  {}", duplicate.second);
+                succeeded = false;
+            }
+        }
+        if show_location && let Some(location) = result.location {
+            println!("  written at  {}", location);
+        }
+    }
+    if ci && needs_blessing > 0 {
+        println!(
+            "{} snapshot(s) of {} need to be blessed locally, with `cargo expect promote {}` or `EXPECTATION_UPDATE=always cargo test`",
+            needs_blessing, name, name
+        );
+    }
+    if ci {
+        if succeeded {
+            let _ = std::fs::remove_dir_all(&output);
+        } else {
+            println!("The actual files and diffs are in {}", output.to_string_lossy());
         }
     }
     if !succeeded {
@@ -104,11 +399,26 @@ pub fn expect<F: FnOnce(Provider)>(name: &str, f: F) {
     }
 }
 
+#[cfg(test)]
 fn validate<Fi: Fn(&Path) -> bool>(
     name: &str,
     fs: Box<dyn FileSystem>,
     provider: Provider,
     filter: Fi,
+) -> Vec<EResult> {
+    validate_indexed(name, fs.duplicate(), fs, provider, filter, None)
+}
+
+/// Like `validate`, but skips comparing pairs of files that `index` says
+/// have not changed since they last compared equal, and keeps `index` up to
+/// date.  Actual files and diffs are in `out_fs`, which is usually `fs`.
+fn validate_indexed<Fi: Fn(&Path) -> bool>(
+    name: &str,
+    fs: Box<dyn FileSystem>,
+    out_fs: Box<dyn FileSystem>,
+    provider: Provider,
+    filter: Fi,
+    mut index: Option<&mut Index>,
 ) -> Vec<EResult> {
     let mut visited = HashSet::new();
     let mut out = Vec::new();
@@ -116,81 +426,164 @@ fn validate<Fi: Fn(&Path) -> bool>(
     let expected_fs = fs
         .subsystem(Path::new("expected"))
         .subsystem(Path::new(name));
-    let actual_fs = fs.subsystem(Path::new("actual")).subsystem(Path::new(name));
-    let diff_fs = fs.subsystem(Path::new("diff")).subsystem(Path::new(name));
+    let actual_fs = out_fs.subsystem(Path::new("actual")).subsystem(Path::new(name));
+    let diff_fs = out_fs.subsystem(Path::new("diff")).subsystem(Path::new(name));
 
     #[allow(unused_variables)]
     let fs = ();
 
-    for (file, eq, diff) in provider.take_files() {
-        if !filter(&file) || visited.contains(&file) {
+    let mut write_errors = provider.take_write_errors();
+
+    // A file that was written more than once can't be compared meaningfully,
+    // so it is only reported as a duplicate.
+    for (file, first, second) in provider.take_duplicates() {
+        if !filter(&file) {
             continue;
         }
         visited.insert(file.clone());
+        out.push(EResult::duplicate_snapshot(name, &file, first, second.clone()).at(second));
+    }
 
-        if !actual_fs.exists(&file) {
-            out.push(EResult::actual_not_found(
-                name,
-                &file,
-                actual_fs.full_path_for(&file),
-                expected_fs.full_path_for(&file),
-            ));
+    let mut files = provider.take_files();
+    let trees = provider.take_trees();
+
+    // Files that the test didn't ask for are compared as bytes if they are
+    // in a directory snapshot, and are unexpected otherwise.
+    for file in actual_fs.files() {
+        if !filter(&file)
+            || visited.contains(&file)
+            || provider::is_temp_path(&file)
+            || files.iter().any(|(f, ..)| *f == file)
+        {
             continue;
         }
+        match trees.iter().find(|(dir, _)| file.starts_with(dir)) {
+            Some((_, location)) => files.push((
+                file,
+                location.clone(),
+                index::comparator_id("bytes"),
+                Box::new(|a, e| streams_eq(a, e)),
+                Box::new(|_, _, _, _| Ok(())),
+            )),
+            None => {
+                visited.insert(file.clone());
+                let actual = actual_fs.full_path_for(&file);
+                out.push(EResult::unexpected_file(name, file, actual));
+            }
+        }
+    }
 
-        if !expected_fs.exists(&file) {
-            out.push(EResult::expected_not_found(
-                name,
-                &file,
-                actual_fs.full_path_for(&file),
-                expected_fs.full_path_for(&file),
-            ));
+    for (file, location, comparator, eq, diff) in files {
+        if !filter(&file) || visited.contains(&file) {
             continue;
         }
+        visited.insert(file.clone());
 
-        let mut is_eq = false;
-        let res = actual_fs.read(&file, &mut |actual_read| {
-            expected_fs.read(&file, &mut |expected_read| {
-                is_eq = eq(actual_read, expected_read)?;
-                Ok(())
-            })
-        });
-
-        let is_eq = match res {
-            Ok(_) => is_eq,
-            Err(e) => {
+        let start = out.len();
+        'file: {
+            if let Some(e) = write_errors.remove(&file) {
                 out.push(EResult::io_error(name, &file, e));
-                continue;
+                break 'file;
+            }
+
+            if !actual_fs.exists(&file) {
+                out.push(EResult::actual_not_found(
+                    name,
+                    &file,
+                    actual_fs.full_path_for(&file),
+                    expected_fs.full_path_for(&file),
+                ));
+                break 'file;
+            }
+
+            if !expected_fs.exists(&file) {
+                out.push(EResult::expected_not_found(
+                    name,
+                    &file,
+                    actual_fs.full_path_for(&file),
+                    expected_fs.full_path_for(&file),
+                ));
+                break 'file;
             }
-        };
 
-        if !is_eq {
-            let mut write_requester = provider::WriteRequester {
-                fs: diff_fs.duplicate(),
-                files: vec![],
+            let key = index::key(name, &file);
+            let digests = match index {
+                Some(_) => match (
+                    index::digest(&*actual_fs, &file),
+                    index::digest(&*expected_fs, &file),
+                ) {
+                    (Ok(a), Ok(e)) => Some((a, e)),
+                    (Err(e), _) | (_, Err(e)) => {
+                        out.push(EResult::io_error(name, &file, e));
+                        break 'file;
+                    }
+                },
+                None => None,
             };
 
-            let diff_result = actual_fs.read(&file, &mut |actual_read| {
+            if let (Some(index), Some((a, e))) = (index.as_deref(), &digests)
+                && index.is_unchanged(&key, a, e, &comparator)
+            {
+                out.push(EResult::unchanged(name, &file));
+                break 'file;
+            }
+
+            let mut is_eq = false;
+            let res = actual_fs.read(&file, &mut |actual_read| {
                 expected_fs.read(&file, &mut |expected_read| {
-                    diff(actual_read, expected_read, &file, &mut write_requester)
+                    is_eq = eq(actual_read, expected_read)?;
+                    Ok(())
                 })
             });
 
-            out.push(EResult::difference(
-                name,
-                &file,
-                actual_fs.full_path_for(&file),
-                expected_fs.full_path_for(&file),
-                write_requester.files,
-            ));
+            let is_eq = match res {
+                Ok(_) => is_eq,
+                Err(e) => {
+                    out.push(EResult::io_error(name, &file, e));
+                    break 'file;
+                }
+            };
 
-            if let Err(e) = diff_result {
-                out.push(EResult::io_error(name, &file, e));
+            if let (Some(index), Some((a, e))) = (index.as_deref_mut(), digests) {
+                if is_eq {
+                    index.record(key, a, e, comparator.clone());
+                } else {
+                    index.forget(key);
+                }
             }
-            continue;
-        }
 
-        out.push(EResult::ok(name, &file));
+            if !is_eq {
+                let mut write_requester = provider::WriteRequester {
+                    fs: diff_fs.duplicate(),
+                    files: vec![],
+                };
+
+                let diff_result = actual_fs.read(&file, &mut |actual_read| {
+                    expected_fs.read(&file, &mut |expected_read| {
+                        diff(actual_read, expected_read, &file, &mut write_requester)
+                    })
+                });
+
+                out.push(EResult::difference(
+                    name,
+                    &file,
+                    actual_fs.full_path_for(&file),
+                    expected_fs.full_path_for(&file),
+                    write_requester.files,
+                ));
+
+                if let Err(e) = diff_result {
+                    out.push(EResult::io_error(name, &file, e));
+                }
+                break 'file;
+            }
+
+            out.push(EResult::ok(name, &file));
+        }
+        for result in &mut out[start..] {
+            result.location = Some(location.clone());
+            result.comparator = Some(comparator.clone());
+        }
     }
 
     for file in expected_fs.files() {
@@ -199,6 +592,9 @@ fn validate<Fi: Fn(&Path) -> bool>(
         }
 
         if !actual_fs.exists(&file) {
+            if let Some(index) = index.as_deref_mut() {
+                index.forget(index::key(name, &file));
+            }
             out.push(EResult::actual_not_found(
                 name,
                 &file,
@@ -1,8 +1,16 @@
-use std::io::{Result as IoResult, Write};
+use std::any::type_name;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{Error as IoError, Result as IoResult, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use expectation_shared::filesystem::{FileSystem, ReadSeek};
+use expectation_shared::index;
+use expectation_shared::Location;
+
+use crate::settings::Settings;
 
 pub struct WriteRequester {
     pub(crate) fs: Box<dyn FileSystem>,
@@ -15,34 +23,63 @@ impl WriteRequester {
         S: AsRef<Path>,
         Fn: for<'a> FnMut(&'a mut dyn Write) -> IoResult<()>,
     {
-        let mut v = vec![];
-        v.push(1u8);
         self.files.push(self.fs.full_path_for(path.as_ref()));
         self.fs.write(path.as_ref(), &mut f)
     }
 }
 
-pub(crate) type Files = Vec<(
-        PathBuf,
-        Box<dyn for<'a> Fn(&'a mut dyn ReadSeek, &'a mut dyn ReadSeek) -> IoResult<bool>>,
-        Box<
-            dyn for<'b> Fn(&'b mut dyn ReadSeek, &'b mut dyn ReadSeek, &'b Path, &'b mut WriteRequester)
-                -> IoResult<()>,
-        >,
-    )>;
+pub(crate) type Compare =
+    Box<dyn for<'a> Fn(&'a mut dyn ReadSeek, &'a mut dyn ReadSeek) -> IoResult<bool> + Send>;
+
+pub(crate) type Diff = Box<
+    dyn for<'b> Fn(&'b mut dyn ReadSeek, &'b mut dyn ReadSeek, &'b Path, &'b mut WriteRequester)
+        -> IoResult<()>
+        + Send,
+>;
+
+/// The files requested by a test, along with where they were requested and
+/// a description of how they are compared, for the index.
+pub(crate) type Files = Vec<(PathBuf, Location, String, Compare, Diff)>;
+
+pub(crate) type WriteErrors = Arc<Mutex<HashMap<PathBuf, IoError>>>;
+
+/// Files that were requested more than once, along with where they were
+/// first requested and where they were requested again.
+pub(crate) type Duplicates = Arc<Mutex<Vec<(PathBuf, Location, Location)>>>;
+
+/// The directories whose whole contents are snapshotted, relative to the
+/// test, along with where they were marked.
+pub(crate) type Trees = Arc<Mutex<Vec<(PathBuf, Location)>>>;
 
 pub struct Provider {
     #[cfg_attr(not(test), allow(dead_code))]
     pub(crate) root_fs: Box<dyn FileSystem>,
     pub(crate) fs: Box<dyn FileSystem>,
     pub(crate) files: Arc<Mutex<Files>>,
+    pub(crate) write_errors: WriteErrors,
+    pub(crate) duplicates: Duplicates,
+    pub(crate) trees: Trees,
+    pub(crate) settings: Arc<Settings>,
     cur_offset: PathBuf,
 }
 
+/// Streams a snapshot to a temporary file next to its final location, and
+/// moves it into place once it is finished or dropped.
 pub struct Writer {
-    inner: Vec<u8>,
+    sink: Option<Box<dyn Write + Send>>,
+    /// The error from opening the temporary file, returned by every write.
+    open_error: Option<IoError>,
     filesystem: Box<dyn FileSystem>,
     path: PathBuf,
+    temp_path: PathBuf,
+    /// The path of the file relative to the test, used to report errors.
+    file: PathBuf,
+    write_errors: WriteErrors,
+    finished: bool,
+    /// Set for text files with redaction rules, in which case the output is
+    /// redacted a line at a time.  `buffer` holds the line being written.
+    redactions: Option<Arc<Settings>>,
+    buffer: Vec<u8>,
 }
 
 impl Clone for Provider {
@@ -51,18 +88,98 @@ impl Clone for Provider {
             root_fs: self.root_fs.duplicate(),
             fs: self.fs.duplicate(),
             files: self.files.clone(),
+            write_errors: self.write_errors.clone(),
+            duplicates: self.duplicates.clone(),
+            trees: self.trees.clone(),
+            settings: self.settings.clone(),
             cur_offset: self.cur_offset.clone(),
         }
     }
 }
 
+fn temp_path_for(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.partial", name, id))
+}
+
+/// Whether `path` is a file that a `Writer` hasn't moved into place yet.
+pub(crate) fn is_temp_path(path: &Path) -> bool {
+    path.file_name()
+        .map(|n| n.to_string_lossy())
+        .map(|n| n.starts_with('.') && n.ends_with(".partial"))
+        .unwrap_or(false)
+}
+
+fn copy_error(e: &IoError) -> IoError {
+    IoError::new(e.kind(), e.to_string())
+}
+
 impl Writer {
-    fn new(filesystem: Box<dyn FileSystem>, path: PathBuf) -> Self {
+    fn new(filesystem: Box<dyn FileSystem>, path: PathBuf, file: PathBuf, write_errors: WriteErrors) -> Self {
+        let temp_path = temp_path_for(&path);
+        let (sink, open_error) = match filesystem.create(&temp_path) {
+            Ok(sink) => (Some(sink), None),
+            Err(e) => (None, Some(e)),
+        };
         Writer {
+            sink,
+            open_error,
             filesystem,
             path,
-            inner: vec![],
+            temp_path,
+            file,
+            write_errors,
+            finished: false,
+            redactions: None,
+            buffer: vec![],
+        }
+    }
+
+    /// Applies the configured redaction rules to everything written.
+    #[cfg(feature = "text")]
+    pub(crate) fn redacted(mut self, settings: &Arc<Settings>) -> Writer {
+        if settings.has_redactions() {
+            self.redactions = Some(settings.clone());
         }
+        self
+    }
+
+    /// Writes the output to the filesystem.  Dropping a `Writer` does the
+    /// same, but any error is only reported once the test is validated.
+    pub fn finish(mut self) -> IoResult<()> {
+        self.write_out()
+    }
+
+    /// Moves the output into place, recording any error so that the file
+    /// is reported as an `IoError` even if the caller ignores it.
+    fn write_out(&mut self) -> IoResult<()> {
+        let result = self.try_write_out();
+        if let Err(e) = &result {
+            self.write_errors
+                .lock()
+                .unwrap()
+                .insert(self.file.clone(), copy_error(e));
+        }
+        result
+    }
+
+    fn try_write_out(&mut self) -> IoResult<()> {
+        self.finished = true;
+        if let Some(e) = self.open_error.take() {
+            return Err(e);
+        }
+        let mut sink = self.sink.take().expect("writer is only finished once");
+        if let Some(settings) = self.redactions.take() {
+            write_redacted(&mut sink, &settings, &self.buffer)?;
+        }
+        sink.flush()?;
+        drop(sink);
+        self.filesystem.rename(&self.temp_path, &self.path)
     }
 }
 
@@ -73,6 +190,10 @@ impl Provider {
             root_fs: self.root_fs.duplicate(),
             fs: self.fs.duplicate().subsystem(path.as_ref()),
             files: self.files.clone(),
+            write_errors: self.write_errors.clone(),
+            duplicates: self.duplicates.clone(),
+            trees: self.trees.clone(),
+            settings: self.settings.clone(),
             cur_offset: self.cur_offset.join(path),
         }
     }
@@ -82,10 +203,18 @@ impl Provider {
             root_fs,
             fs,
             files: Arc::new(Mutex::new(vec![])),
+            write_errors: Arc::new(Mutex::new(HashMap::new())),
+            duplicates: Arc::new(Mutex::new(vec![])),
+            trees: Arc::new(Mutex::new(vec![])),
+            settings: Arc::new(Settings::default()),
             cur_offset: PathBuf::new(),
         }
     }
 
+    pub(crate) fn with_settings(self, settings: Arc<Settings>) -> Provider {
+        Provider { settings, ..self }
+    }
+
     pub(crate) fn take_files(&self) -> Files {
         use std::mem::swap;
         let mut empty = vec![];
@@ -93,44 +222,169 @@ impl Provider {
         swap(&mut empty, &mut lock);
         empty
     }
+
+    pub(crate) fn take_write_errors(&self) -> HashMap<PathBuf, IoError> {
+        ::std::mem::take(&mut *self.write_errors.lock().unwrap())
+    }
+
+    pub(crate) fn take_duplicates(&self) -> Vec<(PathBuf, Location, Location)> {
+        ::std::mem::take(&mut *self.duplicates.lock().unwrap())
+    }
+
+    pub(crate) fn take_trees(&self) -> Vec<(PathBuf, Location)> {
+        ::std::mem::take(&mut *self.trees.lock().unwrap())
+    }
+}
+
+/// Writes `line` with the redactions applied, or as it is if it isn't text.
+fn write_redacted(sink: &mut dyn Write, settings: &Settings, line: &[u8]) -> IoResult<()> {
+    match ::std::str::from_utf8(line) {
+        Ok(text) => sink.write_all(settings.redact(text).as_bytes()),
+        Err(_) => sink.write_all(line),
+    }
 }
 
 impl Write for Writer {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.inner.write(buf)
+        match (&mut self.sink, &self.open_error) {
+            (Some(sink), _) if self.redactions.is_some() => {
+                let settings = self.redactions.as_ref().expect("checked above");
+                self.buffer.extend_from_slice(buf);
+                while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                    write_redacted(sink, settings, &line)?;
+                }
+                Ok(buf.len())
+            }
+            (Some(sink), _) => sink.write(buf),
+            (None, Some(e)) => Err(copy_error(e)),
+            (None, None) => unreachable!(),
+        }
     }
     fn flush(&mut self) -> IoResult<()> {
-        self.inner.flush()
+        match (&mut self.sink, &self.open_error) {
+            (Some(sink), _) => sink.flush(),
+            (None, Some(e)) => Err(copy_error(e)),
+            (None, None) => unreachable!(),
+        }
     }
 }
 
 impl Drop for Writer {
     fn drop(&mut self) {
-        let mut contents = Vec::new();
-        ::std::mem::swap(&mut contents, &mut self.inner);
-        // TODO: maybe don't ignore?
-        let _ = self
-            .filesystem
-            .write(&self.path, &mut |w| w.write_all(&contents));
+        if self.finished {
+            return;
+        }
+        let _ = self.write_out();
     }
 }
 
+/// Identifies a comparison by the types of its functions, which tell apart
+/// the closures of different extensions, and by the options it was made with.
+fn comparator<C, D>(options: &(impl Debug + ?Sized)) -> String {
+    index::comparator_id(&format!("{} {} {:?}", type_name::<C>(), type_name::<D>(), options))
+}
+
 impl Provider {
+    /// Registers `name` to be compared with `compare` and diffed with `diff`
+    /// once the test finishes.  Requesting the same file twice in one test
+    /// is reported as a duplicate snapshot, pointing at both callers.
+    #[track_caller]
     pub fn custom_test<S, C, D>(&self, name: S, compare: C, diff: D) -> Writer
     where
         S: AsRef<Path>,
-        C: for<'a> Fn(&'a mut dyn ReadSeek, &'a mut dyn ReadSeek) -> IoResult<bool> + 'static,
+        C: for<'a> Fn(&'a mut dyn ReadSeek, &'a mut dyn ReadSeek) -> IoResult<bool> + Send + 'static,
+        D: for<'b> Fn(&'b mut dyn ReadSeek, &'b mut dyn ReadSeek, &'b Path, &'b mut WriteRequester) -> IoResult<()>
+            + Send
+            + 'static,
+    {
+        self.custom_test_with(name, &(), compare, diff)
+    }
+
+    /// Like `custom_test`, for comparisons that depend on `options`.  Files
+    /// that the index remembers as equal are compared again once `options`
+    /// change, so the options have to be part of their `Debug` output.
+    #[track_caller]
+    pub fn custom_test_with<S, O, C, D>(&self, name: S, options: &O, compare: C, diff: D) -> Writer
+    where
+        S: AsRef<Path>,
+        O: Debug + ?Sized,
+        C: for<'a> Fn(&'a mut dyn ReadSeek, &'a mut dyn ReadSeek) -> IoResult<bool> + Send + 'static,
         D: for<'b> Fn(&'b mut dyn ReadSeek, &'b mut dyn ReadSeek, &'b Path, &'b mut WriteRequester) -> IoResult<()>
+            + Send
             + 'static,
     {
         let name: PathBuf = name.as_ref().into();
+        let location = Location::from(std::panic::Location::caller());
+        let comparator = comparator::<C, D>(options);
+        let file = self.register(&name, location, comparator, Box::new(compare), Box::new(diff));
+        Writer::new(self.fs.duplicate(), name, file, self.write_errors.clone())
+    }
+
+    /// Registers `name` like `custom_test`, but instead of a writer returns
+    /// the real path of the actual file, for when something outside of the
+    /// test has to write it, like a program that is given the path on its
+    /// command line.  The directory that the file goes in is created; the
+    /// file itself is left for the caller to write.
+    #[track_caller]
+    pub fn path_for<S, C, D>(&self, name: S, compare: C, diff: D) -> IoResult<PathBuf>
+    where
+        S: AsRef<Path>,
+        C: for<'a> Fn(&'a mut dyn ReadSeek, &'a mut dyn ReadSeek) -> IoResult<bool> + Send + 'static,
+        D: for<'b> Fn(&'b mut dyn ReadSeek, &'b mut dyn ReadSeek, &'b Path, &'b mut WriteRequester) -> IoResult<()>
+            + Send
+            + 'static,
+    {
+        let name = name.as_ref();
+        let location = Location::from(std::panic::Location::caller());
+        self.register(name, location, comparator::<C, D>(&()), Box::new(compare), Box::new(diff));
+        if let Some(parent) = name.parent() {
+            self.fs.create_dir_all(parent)?;
+        }
+        Ok(self.fs.full_path_for(name))
+    }
+
+    /// Adds `name` to the files that are compared once the test finishes,
+    /// returning its path relative to the test.
+    fn register(
+        &self,
+        name: &Path,
+        location: Location,
+        comparator: String,
+        compare: Compare,
+        diff: Diff,
+    ) -> PathBuf {
+        let file = self.cur_offset.join(name);
         let mut lock = self.files.lock().unwrap();
-        lock
-            .push((
-                self.cur_offset.join(name.clone()),
-                Box::new(compare),
-                Box::new(diff)));
-        Writer::new(self.fs.duplicate(), name )
+        match lock.iter().find(|(f, ..)| *f == file) {
+            Some((_, first, ..)) => {
+                self.duplicates
+                    .lock()
+                    .unwrap()
+                    .push((file.clone(), first.clone(), location));
+            }
+            None => lock.push((file.clone(), location, comparator, compare, diff)),
+        }
+        file
+    }
+
+    /// Snapshots the whole directory of this provider: besides the files
+    /// requested through the provider, every file that ends up in the
+    /// directory is compared byte for byte with its expected file.  This is
+    /// for files that are written by other means, like a program under test
+    /// that is given the returned path, which is where the directory is in
+    /// the actual folder.  The directory may not exist yet.
+    ///
+    /// Without this, a file in the actual folder that the test didn't
+    /// request is reported as unexpected.
+    #[track_caller]
+    pub fn directory_snapshot(&self) -> PathBuf {
+        let location = Location::from(std::panic::Location::caller());
+        self.trees
+            .lock()
+            .unwrap()
+            .push((self.cur_offset.clone(), location));
+        self.fs.full_path_for(Path::new("")).components().collect()
     }
 }
 
@@ -140,7 +394,12 @@ fn writer_does_not_write_to_filesystem_if_not_written_to() {
     use expectation_shared::filesystem::*;
     let filesystem = Box::new(FakeFileSystem::new()) as Box<dyn FileSystem>;
     {
-        let _writer = Writer::new(filesystem.duplicate(), "foo.txt".into());
+        let _writer = Writer::new(
+            filesystem.duplicate(),
+            "foo.txt".into(),
+            "foo.txt".into(),
+            Default::default(),
+        );
     }
     assert!(filesystem.exists(Path::new("foo.txt")));
 }
@@ -0,0 +1,41 @@
+use expectation_shared::config::Config;
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+
+/// The configuration of the crate under test, with its redaction rules
+/// compiled.
+#[derive(Debug, Default)]
+pub(crate) struct Settings {
+    pub(crate) config: Config,
+    redactions: Vec<(Regex, String)>,
+}
+
+impl Settings {
+    pub(crate) fn new(config: Config) -> IoResult<Settings> {
+        let redactions = config
+            .redactions
+            .iter()
+            .map(|r| {
+                Regex::new(&r.pattern)
+                    .map(|regex| (regex, r.replacement.clone()))
+                    .map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+            }).collect::<IoResult<_>>()?;
+        Ok(Settings { config, redactions })
+    }
+
+    #[cfg(feature = "text")]
+    pub(crate) fn has_redactions(&self) -> bool {
+        !self.redactions.is_empty()
+    }
+
+    pub(crate) fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for (regex, replacement) in &self.redactions {
+            if let Cow::Owned(replaced) = regex.replace_all(&text, replacement.as_str()) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+}
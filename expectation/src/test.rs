@@ -1,17 +1,11 @@
 use super::extensions::*;
 use super::*;
-use expectation_shared::Result as EResult;
-use std::io::{Read, Result as IoResult};
+use expectation_shared::{Location, Result as EResult};
+use std::io::{BufRead, Read, Result as IoResult, Write};
 use expectation_shared::filesystem;
 
-fn byte_for_byte_equality<R1: Read, R2: Read>(mut r1: R1, mut r2: R2) -> IoResult<bool> {
-    let mut v1 = vec![];
-    let mut v2 = vec![];
-
-    r1.read_to_end(&mut v1)?;
-    r2.read_to_end(&mut v2)?;
-
-    Ok(v1 == v2)
+fn byte_for_byte_equality<R1: BufRead, R2: BufRead>(r1: R1, r2: R2) -> IoResult<bool> {
+    streams_eq(r1, r2)
 }
 
 fn byte_for_byte_diff<R1: Read, R2: Read>(
@@ -63,7 +57,72 @@ pub fn difftest_validate<F: FnOnce(Provider)>(name: &str, f: F) -> Vec<EResult>
             .subsystem(Path::new(name)),
     );
     f(provider.clone());
-    validate(name, top_fs.duplicate(), provider, |_| true)
+    without_locations(validate(name, top_fs.duplicate(), provider, |_| true))
+}
+
+#[cfg(test)]
+pub fn difftest_validate_fs<F: FnOnce(Provider)>(name: &str, f: F) -> (Vec<EResult>, FakeFileSystem) {
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(
+        top_fs.duplicate(),
+        top_fs
+            .subsystem(Path::new("actual"))
+            .subsystem(Path::new(name)),
+    );
+    f(provider.clone());
+    let results = validate(name, top_fs.duplicate(), provider, |_| true);
+    (without_locations(results), top_fs)
+}
+
+/// Most tests only care about what was found, not where it was written.
+#[cfg(test)]
+fn without_locations(results: Vec<EResult>) -> Vec<EResult> {
+    results
+        .into_iter()
+        .map(|r| EResult { location: None, comparator: None, ..r })
+        .collect()
+}
+
+/// A directory in the real temp dir that is removed when the test is done,
+/// even if it panics.
+#[cfg(test)]
+struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("expectation-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+fn read_to_string(fs: &FakeFileSystem, path: &str) -> String {
+    let mut v = String::new();
+    fs.read(Path::new(path), &mut |r| r.read_to_string(&mut v).map(|_| ()))
+        .unwrap();
+    v
+}
+
+#[test]
+fn streams_eq_compares_across_buffer_boundaries() {
+    use std::io::BufReader;
+    let small = |s: &'static str| BufReader::with_capacity(3, s.as_bytes());
+    let large = |s: &'static str| BufReader::with_capacity(5, s.as_bytes());
+
+    assert!(streams_eq(small("hello world"), large("hello world")).unwrap());
+    assert!(!streams_eq(small("hello world"), large("hello wordl")).unwrap());
+    assert!(!streams_eq(small("hello world"), large("hello world!")).unwrap());
+    assert!(!streams_eq(small("hello world!"), large("hello world")).unwrap());
+    assert!(streams_eq(small(""), large("")).unwrap());
 }
 
 #[test]
@@ -280,3 +339,1103 @@ fn validate_one_file_diff_is_bad_with_text_extension() {
         )]
     );
 }
+
+#[cfg(feature = "table")]
+#[test]
+fn validate_table_matches_rows_by_key_and_tolerance() {
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.csv"), &mut |writer| {
+                write!(writer, "1,alice,3.5\n2,bob,4.0\n")
+            }).unwrap();
+
+        let options = TableOptions {
+            key_columns: vec![0],
+            numeric_tolerance: 0.01,
+        };
+        provider
+            .csv_with("foo.csv", vec![vec!["2", "bob", "4.001"], vec!["1", "alice", "3.5"]], options)
+            .unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.csv")]);
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn validate_table_diff_marks_cells_and_rows() {
+    let (results, fs) = difftest_validate_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.csv"), &mut |writer| {
+                write!(writer, "id,name\n1,alice\n2,bob\n3,carol\n")
+            }).unwrap();
+
+        provider
+            .csv("foo.csv", vec![vec!["id", "name"], vec!["1", "alice"], vec!["2", "bobby"], vec!["4", "dave"], vec!["5", "eve"]])
+            .unwrap();
+    });
+
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.csv",
+            "/actual/hi/foo.csv",
+            "/expected/hi/foo.csv",
+            vec!["/diff/hi/foo.csv.diff".into()],
+        )]
+    );
+    assert_eq!(
+        read_to_string(&fs, "diff/hi/foo.csv.diff"),
+        "# + inserted (actual only)\n\
+         # - deleted (expected only)\n\
+         # ~ changed, cells are marked as [expected → actual]\n  \
+         | id      | name\n  \
+         | 1       | alice\n\
+         ~ | 2       | [bob → bobby]\n\
+         ~ | [3 → 4] | [carol → dave]\n\
+         + | 5       | eve\n"
+    );
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_difference_writes_pixel_diff() {
+    use image::{Rgb, RgbImage};
+    let (results, fs) = difftest_validate_fs("hi", |provider| {
+        provider.rgb_image("foo.png", RgbImage::new(2, 2)).unwrap();
+        let mut expected = RgbImage::new(2, 2);
+        expected.put_pixel(1, 0, Rgb([0, 0, 255]));
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        let expected_provider = provider::Provider::new(provider.root_fs.duplicate(), expected_fs);
+        expected_provider.rgb_image("foo.png", expected).unwrap();
+    });
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.png",
+            "/actual/hi/foo.png",
+            "/expected/hi/foo.png",
+            vec!["/diff/hi/foo.png/diff.png".into()],
+        )]
+    );
+
+    let mut bytes = vec![];
+    fs.read(Path::new("diff/hi/foo.png/diff.png"), &mut |r| r.read_to_end(&mut bytes).map(|_| ()))
+        .unwrap();
+    let diff = image::load_from_memory(&bytes).unwrap().to_rgba8();
+    assert_eq!(diff.get_pixel(1, 0).0, [255, 0, 0, 255]);
+    assert_eq!(diff.get_pixel(0, 0).0, [192, 192, 192, 255]);
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn validate_image_size_difference_is_described() {
+    use image::RgbaImage;
+    let results = difftest_validate("hi", |provider| {
+        provider.rgba_image("foo.png", RgbaImage::new(2, 2)).unwrap();
+        let expected_fs = provider.root_fs.subsystem(Path::new("expected/hi"));
+        let expected_provider = provider::Provider::new(provider.root_fs.duplicate(), expected_fs);
+        expected_provider.rgba_image("foo.png", RgbaImage::new(3, 2)).unwrap();
+    });
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.png",
+            "/actual/hi/foo.png",
+            "/expected/hi/foo.png",
+            vec!["/diff/hi/foo.png/img-size.txt".into()],
+        )]
+    );
+}
+
+#[cfg(feature = "svg")]
+#[test]
+fn validate_svg_ignores_attribute_order_and_precision() {
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.svg"), &mut |writer| {
+                write!(
+                    writer,
+                    r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10">
+                        <!-- a comment -->
+                        <rect y="2" x="1.00001" width="5" height="5"/>
+                    </svg>"#
+                )
+            }).unwrap();
+
+        provider
+            .svg(
+                "foo.svg",
+                r#"<svg height="10" width="10" xmlns="http://www.w3.org/2000/svg"><rect x="1" y="2.0" height="5" width="5"/></svg>"#,
+            ).unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.svg")]);
+}
+
+#[cfg(feature = "svg")]
+#[test]
+fn validate_svg_only_rounds_numeric_attributes() {
+    let svg = |fill: &str, id: &str| {
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect id="{}" fill="{}" width="5" height="5"/></svg>"##,
+            id, fill
+        )
+    };
+    for (expected, actual) in [
+        (svg("#00ff00", "a1"), svg("#000ff0", "a1")),
+        (svg("#00ff00", "a01"), svg("#00ff00", "a1")),
+    ] {
+        let results = difftest_validate("hi", |provider| {
+            provider
+                .root_fs
+                .write(Path::new("expected/hi/foo.svg"), &mut |writer| write!(writer, "{}", expected))
+                .unwrap();
+            provider.svg("foo.svg", &actual).unwrap();
+        });
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0].kind, ResultKind::Difference(_)), "{:?}", results);
+    }
+}
+
+#[cfg(feature = "svg")]
+#[test]
+fn validate_svg_difference_is_rasterized() {
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.svg"), &mut |writer| {
+                write!(
+                    writer,
+                    r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="5" height="5"/></svg>"#
+                )
+            }).unwrap();
+
+        provider
+            .svg(
+                "foo.svg",
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="6" height="5"/></svg>"#,
+            ).unwrap();
+    });
+
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.svg",
+            "/actual/hi/foo.svg",
+            "/expected/hi/foo.svg",
+            vec![
+                "/diff/hi/foo.svg/structure.diff".into(),
+                "/diff/hi/foo.svg/actual.png".into(),
+                "/diff/hi/foo.svg/expected.png".into(),
+                "/diff/hi/foo.svg/diff.png".into(),
+            ],
+        )]
+    );
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn validate_html_ignores_attribute_order_and_whitespace() {
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.html"), &mut |writer| {
+                write!(
+                    writer,
+                    "<!DOCTYPE html>\n<html>\n  <body>\n    <p id=\"a\" class=\"b\">hello\n      world</p>\n    <br>\n  </body>\n</html>\n"
+                )
+            }).unwrap();
+
+        provider
+            .html(
+                "foo.html",
+                "<html><body><p class=b id='a'>hello world</p><br/></body></html>",
+            ).unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.html")]);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn validate_html_keeps_text_after_a_bare_less_than() {
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.html"), &mut |writer| {
+                write!(writer, "<html><body><p>1 < 3</p><p>after</p></body></html>")
+            }).unwrap();
+        provider
+            .html("foo.html", "<html><body><p>1 < 2</p><p>after</p></body></html>")
+            .unwrap();
+    });
+    assert_eq!(results.len(), 1);
+    assert!(matches!(results[0].kind, ResultKind::Difference(_)), "{:?}", results);
+
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.html"), &mut |writer| {
+                write!(writer, "<p>1 &lt; 2</p><p>after</p>")
+            }).unwrap();
+        provider.html("foo.html", "<p>1 < 2</p><p>after</p>").unwrap();
+    });
+    assert_eq!(results, vec![EResult::ok("hi", "foo.html")]);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn validate_html_diff_reports_element_paths() {
+    let (results, fs) = difftest_validate_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.html"), &mut |writer| {
+                write!(
+                    writer,
+                    "<html><body><div>one</div><div class=\"x\">two</div></body></html>"
+                )
+            }).unwrap();
+
+        provider
+            .html(
+                "foo.html",
+                "<html><body><div>one</div><div class=\"y\">two</div><span>new</span></body></html>",
+            ).unwrap();
+    });
+
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.html",
+            "/actual/hi/foo.html",
+            "/expected/hi/foo.html",
+            vec![
+                "/diff/hi/foo.html.tree.diff".into(),
+                "/diff/hi/foo.html.diff".into(),
+            ],
+        )]
+    );
+    assert_eq!(
+        read_to_string(&fs, "diff/hi/foo.html.tree.diff"),
+        "~ /html/body/div[2]/@class: \"x\" → \"y\"\n\
+         + /html/body/span: <span> ...\n"
+    );
+    assert_eq!(
+        read_to_string(&fs, "diff/hi/foo.html.diff"),
+        "--- expected\n\
+         +++ actual\n\
+         @@ -3,8 +3,11 @@\n     \
+         <div>\n       \
+         one\n     \
+         </div>\n\
+         -    <div class=\"x\">\n\
+         +    <div class=\"y\">\n       \
+         two\n     \
+         </div>\n\
+         +    <span>\n\
+         +      new\n\
+         +    </span>\n   \
+         </body>\n \
+         </html>\n"
+    );
+}
+
+#[cfg(feature = "wav")]
+#[cfg(test)]
+fn expected_provider(provider: &Provider) -> Provider {
+    Provider::new(
+        provider.root_fs.duplicate(),
+        provider.root_fs.subsystem(Path::new("expected/hi")),
+    )
+}
+
+#[cfg(feature = "wav")]
+#[cfg(test)]
+fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|i| (i as f32 * frequency * 2.0 * std::f32::consts::PI / 8000.0).sin() * 0.5)
+        .collect()
+}
+
+#[cfg(feature = "wav")]
+#[test]
+fn validate_wav_within_tolerance() {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: 8000,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let results = difftest_validate("hi", |provider| {
+        let expected = sine(440.0, 800);
+        expected_provider(&provider).wav("foo.wav", spec, &expected).unwrap();
+
+        let actual: Vec<f32> = expected.iter().map(|s| s + 0.001).collect();
+        provider.wav_with_tolerance("foo.wav", spec, &actual, 0.002).unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.wav")]);
+}
+
+#[cfg(feature = "wav")]
+#[test]
+fn validate_wav_difference_writes_summary_and_waveform() {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: 8000,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let (results, fs) = difftest_validate_fs("hi", |provider| {
+        expected_provider(&provider).wav("foo.wav", spec, &sine(440.0, 800)).unwrap();
+
+        let mut actual = sine(440.0, 800);
+        actual[100] += 0.25;
+        provider.wav("foo.wav", spec, &actual).unwrap();
+    });
+
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.wav",
+            "/actual/hi/foo.wav",
+            "/expected/hi/foo.wav",
+            vec![
+                "/diff/hi/foo.wav/summary.txt".into(),
+                "/diff/hi/foo.wav/waveform.png".into(),
+            ],
+        )]
+    );
+    let summary = read_to_string(&fs, "diff/hi/foo.wav/summary.txt");
+    assert!(summary.contains("out of tolerance:  1\n"), "{}", summary);
+    assert!(summary.contains("peak error:        0.250000 (frame 100, channel 0)"), "{}", summary);
+}
+
+#[test]
+fn validate_text_within_numeric_tolerance() {
+    let results = difftest_validate("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "x1 = 0.30000000000000004, y = 1e3, z = -2.5")
+            }).unwrap();
+
+        let tolerance = NumericTolerance {
+            absolute: 0.0,
+            relative: 1e-6,
+            ulps: 4,
+        };
+        provider
+            .text_with_tolerance("foo.txt", "x1 = 0.3, y = 1000.0001, z = -2.5", tolerance)
+            .unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.txt")]);
+}
+
+#[test]
+fn validate_text_numeric_tolerance_marks_numbers() {
+    let (results, fs) = difftest_validate_fs("hi", |provider| {
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "header\na = 1.0 b = 2.0\nfooter\n")
+            }).unwrap();
+
+        let tolerance = NumericTolerance {
+            absolute: 0.01,
+            ..NumericTolerance::default()
+        };
+        provider
+            .text_with_tolerance("foo.txt", "header\na = 1.001 b = 2.5\nfooter\n", tolerance)
+            .unwrap();
+    });
+
+    assert_eq!(
+        results,
+        vec![EResult::difference(
+            "hi",
+            "foo.txt",
+            "/actual/hi/foo.txt",
+            "/expected/hi/foo.txt",
+            vec!["/diff/hi/foo.txt.diff".into()],
+        )]
+    );
+    assert_eq!(
+        read_to_string(&fs, "diff/hi/foo.txt.diff"),
+        " header\n+a = 1.001 b = 2.5\n-a = 1.0 b = 2.0\n?              ^^^\n footer\n"
+    );
+}
+
+#[test]
+fn validate_indexed_skips_unchanged_files() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    top_fs
+        .write(Path::new("expected/hi/foo.txt"), &mut |writer| write!(writer, "foo"))
+        .unwrap();
+    let mut index = Index::default();
+    let run = |index: &mut Index| {
+        let provider = provider::Provider::new(
+            top_fs.duplicate(),
+            top_fs.subsystem(Path::new("actual")).subsystem(Path::new("hi")),
+        );
+        provider.text("foo.txt", "foo").unwrap();
+        without_locations(validate_indexed(
+            "hi",
+            top_fs.duplicate(),
+            top_fs.duplicate(),
+            provider,
+            |_| true,
+            Some(index),
+        ))
+    };
+
+    assert_eq!(run(&mut index), vec![EResult::ok("hi", "foo.txt")]);
+    assert_eq!(run(&mut index), vec![EResult::unchanged("hi", "foo.txt")]);
+
+    top_fs
+        .write(Path::new("expected/hi/foo.txt"), &mut |writer| write!(writer, "bar"))
+        .unwrap();
+    let results = run(&mut index);
+    assert_eq!(results.len(), 1);
+    assert!(!results[0].is_ok());
+    assert!(!results[0].unchanged);
+}
+
+#[test]
+fn validate_indexed_compares_again_with_another_comparator() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    top_fs
+        .write(Path::new("expected/hi/foo.txt"), &mut |writer| write!(writer, "x = 1.0"))
+        .unwrap();
+    let mut index = Index::default();
+    let run = |index: &mut Index, tolerance: Option<NumericTolerance>| {
+        let provider = provider::Provider::new(
+            top_fs.duplicate(),
+            top_fs.subsystem(Path::new("actual")).subsystem(Path::new("hi")),
+        );
+        match tolerance {
+            Some(tolerance) => provider.text_with_tolerance("foo.txt", "x = 1.0", tolerance).unwrap(),
+            None => provider.text("foo.txt", "x = 1.0").unwrap(),
+        }
+        without_locations(validate_indexed(
+            "hi",
+            top_fs.duplicate(),
+            top_fs.duplicate(),
+            provider,
+            |_| true,
+            Some(index),
+        ))
+    };
+    let loose = NumericTolerance { absolute: 0.5, ..Default::default() };
+    let looser = NumericTolerance { absolute: 1.0, ..Default::default() };
+
+    assert_eq!(run(&mut index, None), vec![EResult::ok("hi", "foo.txt")]);
+    assert_eq!(run(&mut index, Some(loose)), vec![EResult::ok("hi", "foo.txt")]);
+    assert_eq!(run(&mut index, Some(looser)), vec![EResult::ok("hi", "foo.txt")]);
+    assert_eq!(run(&mut index, Some(looser)), vec![EResult::unchanged("hi", "foo.txt")]);
+}
+
+#[test]
+fn validate_reports_duplicate_snapshots() {
+    let mut lines = vec![];
+    let results = difftest_validate("hi", |provider| {
+        lines.push(line!() + 1);
+        provider.text("sub/foo.txt", "one").unwrap();
+        lines.push(line!() + 1);
+        provider.subdir("sub").text("foo.txt", "two").unwrap();
+    });
+
+    let first = Location {
+        file: file!().into(),
+        line: lines[0],
+        column: 18,
+    };
+    let second = Location {
+        file: file!().into(),
+        line: lines[1],
+        column: 32,
+    };
+    assert_eq!(
+        results,
+        vec![EResult::duplicate_snapshot("hi", "sub/foo.txt", first, second)]
+    );
+}
+
+#[test]
+fn validate_records_where_files_were_written() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(
+        top_fs.duplicate(),
+        top_fs.subsystem(Path::new("actual")).subsystem(Path::new("hi")),
+    );
+    let line = line!() + 1;
+    provider.text("foo.txt", "foo").unwrap();
+    let results: Vec<_> = validate("hi", top_fs.duplicate(), provider, |_| true)
+        .into_iter()
+        .map(|r| EResult { comparator: None, ..r })
+        .collect();
+
+    let location = Location {
+        file: file!().into(),
+        line,
+        column: 14,
+    };
+    assert_eq!(
+        results,
+        vec![EResult::expected_not_found(
+            "hi",
+            "foo.txt",
+            "/actual/hi/foo.txt",
+            "/expected/hi/foo.txt",
+        ).at(location)]
+    );
+}
+
+#[test]
+fn provider_can_be_used_from_other_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Provider>();
+    fn assert_send<T: Send>() {}
+    assert_send::<Writer>();
+
+    let results = difftest_validate("hi", |provider| {
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let provider = provider.clone();
+                scope.spawn(move || {
+                    provider.text(format!("{}.txt", i), i.to_string()).unwrap();
+                });
+            }
+        });
+    });
+
+    assert_eq!(results.len(), 4);
+    assert!(results
+        .iter()
+        .all(|r| matches!(r.kind, ResultKind::ExpectedNotFound(_))));
+}
+
+#[test]
+fn block_on_waits_for_wakeups_from_other_threads() {
+    use std::sync::mpsc;
+    use std::task::Poll;
+
+    let (send, recv) = mpsc::channel::<u32>();
+    let mut started = false;
+    let future = std::future::poll_fn(move |cx| {
+        if !started {
+            started = true;
+            let waker = cx.waker().clone();
+            let send = send.clone();
+            std::thread::spawn(move || {
+                send.send(42).unwrap();
+                waker.wake();
+            });
+        }
+        match recv.try_recv() {
+            Ok(value) => Poll::Ready(value),
+            Err(_) => Poll::Pending,
+        }
+    });
+
+    assert_eq!(block_on(future), 42);
+}
+
+#[test]
+fn config_is_read_from_package_metadata() {
+    let manifest = r#"
+        [package]
+        name = "foo"
+
+        [package.metadata.expectation]
+        root = "snapshots"
+        filetypes = ["txt"]
+        tolerance = { relative = 1e-6 }
+        redactions = [{ pattern = "[0-9]+ms", replacement = "<time>" }]
+        report = { json = "target/report.json" }
+    "#;
+    let config = Config::from_manifest(manifest).unwrap();
+
+    assert_eq!(config.root, Path::new("snapshots"));
+    assert_eq!(config.tolerance.relative, 1e-6);
+    assert_eq!(config.redactions[0].replacement, "<time>");
+    assert_eq!(config.report.json, Some("target/report.json".into()));
+    assert!(config.accepts(Path::new("a.txt"), None));
+    assert!(!config.accepts(Path::new("a.png"), None));
+    assert!(config.accepts(Path::new("a.png"), Some(&["png".to_string()])));
+
+    assert_eq!(Config::from_manifest("[package]\nname = \"foo\"").unwrap(), Config::default());
+    assert!(Config::parse("unknown = 1").is_err());
+}
+
+#[test]
+fn configured_redactions_and_tolerance_apply_to_text() {
+    let config = Config::parse(
+        r#"
+        tolerance = { absolute = 0.01 }
+        redactions = [{ pattern = "took [0-9]+ms", replacement = "took <time>" }]
+        "#,
+    ).unwrap();
+    let settings = Arc::new(settings::Settings::new(config).unwrap());
+
+    let (results, fs) = difftest_validate_fs("hi", |provider| {
+        let provider = provider.with_settings(settings);
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.txt"), &mut |writer| {
+                write!(writer, "x = 1.0, took <time>\ny took <time>")
+            }).unwrap();
+
+        let mut w = provider.text_writer("foo.txt");
+        write!(w, "x = 1.001, took ").unwrap();
+        write!(w, "35ms\ny took 4").unwrap();
+        write!(w, "2ms").unwrap();
+        w.finish().unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.txt")]);
+    assert_eq!(
+        read_to_string(&fs, "actual/hi/foo.txt"),
+        "x = 1.001, took <time>\ny took <time>"
+    );
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn configured_tolerance_applies_to_tables() {
+    let config = Config::parse("tolerance = { absolute = 0.01 }").unwrap();
+    let settings = Arc::new(settings::Settings::new(config).unwrap());
+
+    let (results, _) = difftest_validate_fs("hi", |provider| {
+        let provider = provider.with_settings(settings);
+        assert_eq!(provider.default_table_options().numeric_tolerance, 0.01);
+        provider
+            .root_fs
+            .write(Path::new("expected/hi/foo.csv"), &mut |writer| writeln!(writer, "x,1.0"))
+            .unwrap();
+        provider.csv("foo.csv", vec![vec!["x", "1.001"]]).unwrap();
+    });
+
+    assert_eq!(results, vec![EResult::ok("hi", "foo.csv")]);
+}
+
+#[test]
+fn snapshot_root_is_resolved_against_the_crate() {
+    let temp = TempDir::new("root");
+    let crate_dir = temp.0.canonicalize().unwrap();
+    let dir = crate_dir.to_str().unwrap();
+
+    let run = start(dir, None, "expectation_test_foo").unwrap();
+    assert_eq!(run.root, crate_dir.join("expectation-tests"));
+
+    std::fs::write(crate_dir.join("expectation.toml"), "root = \"snapshots\"").unwrap();
+    let run = start(dir, None, "expectation_test_foo").unwrap();
+    assert_eq!(run.root, crate_dir.join("snapshots"));
+
+    let run = start(dir, Some("tests/snapshots"), "expectation_test_foo").unwrap();
+    assert_eq!(run.name, "foo");
+    assert_eq!(run.root, crate_dir.join("tests/snapshots"));
+}
+
+#[test]
+fn update_promotes_new_and_changed_files() {
+    let temp = TempDir::new("update");
+    let root = &temp.0;
+    let write = |path: &str, contents: &str| {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    };
+    write("actual/hi/new.txt", "new");
+    write("actual/hi/changed.txt", "after");
+    write("expected/hi/changed.txt", "before");
+    write("expected/hi/stale.txt", "stale");
+    let results = || {
+        vec![
+            EResult::expected_not_found("hi", "new.txt", root.join("actual/hi/new.txt"), root.join("expected/hi/new.txt")),
+            EResult::difference("hi", "changed.txt", root.join("actual/hi/changed.txt"), root.join("expected/hi/changed.txt"), vec![]),
+            EResult::actual_not_found("hi", "stale.txt", root.join("actual/hi/stale.txt"), root.join("expected/hi/stale.txt")),
+        ]
+    };
+
+    let updated = update(UpdateMode::New, results());
+    assert_eq!(updated.iter().filter(|r| r.is_ok()).count(), 1);
+    assert_eq!(std::fs::read_to_string(root.join("expected/hi/new.txt")).unwrap(), "new");
+    assert_eq!(std::fs::read_to_string(root.join("expected/hi/changed.txt")).unwrap(), "before");
+
+    let updated = update(UpdateMode::Always, results());
+    assert!(updated.iter().all(|r| r.is_ok()), "{:?}", updated);
+    assert_eq!(std::fs::read_to_string(root.join("expected/hi/changed.txt")).unwrap(), "after");
+    assert!(!root.join("expected/hi/stale.txt").exists());
+
+    // Both updates belong to this test run, so one undo reverts them.
+    let snapshots = filesystem::RealFileSystem { root: root.clone() };
+    assert_eq!(expectation_shared::history::batches(&snapshots), vec![1]);
+    expectation_shared::history::undo(&snapshots, None).unwrap();
+    assert!(!root.join("expected/hi/new.txt").exists());
+    assert_eq!(std::fs::read_to_string(root.join("expected/hi/changed.txt")).unwrap(), "before");
+    assert_eq!(std::fs::read_to_string(root.join("expected/hi/stale.txt")).unwrap(), "stale");
+}
+
+#[test]
+fn validate_reads_actual_files_from_the_output_directory() {
+    let snapshots = filesystem::FakeFileSystem::new();
+    let output = filesystem::FakeFileSystem::new().subsystem(Path::new("tmp"));
+    snapshots
+        .write(Path::new("expected/hi/foo.txt"), &mut |writer| write!(writer, "foo"))
+        .unwrap();
+    let provider = provider::Provider::new(
+        snapshots.duplicate(),
+        output.subsystem(Path::new("actual")).subsystem(Path::new("hi")),
+    );
+    provider.text("foo.txt", "bar").unwrap();
+    provider.text("new.txt", "new").unwrap();
+
+    let results = without_locations(validate_indexed(
+        "hi",
+        snapshots.duplicate(),
+        output.duplicate(),
+        provider,
+        |_| true,
+        None,
+    ));
+
+    assert_eq!(
+        results,
+        vec![
+            EResult::difference(
+                "hi",
+                "foo.txt",
+                "/tmp/actual/hi/foo.txt",
+                "/expected/hi/foo.txt",
+                vec!["/tmp/diff/hi/foo.txt.diff".into()],
+            ),
+            EResult::expected_not_found("hi", "new.txt", "/tmp/actual/hi/new.txt", "/expected/hi/new.txt"),
+        ]
+    );
+    assert_eq!(snapshots.files(), vec![PathBuf::from("expected/hi/foo.txt")]);
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct ReadOnlyFileSystem(FakeFileSystem);
+
+#[cfg(test)]
+impl FileSystem for ReadOnlyFileSystem {
+    fn duplicate(&self) -> Box<dyn FileSystem> {
+        Box::new(self.clone())
+    }
+    fn subsystem(&self, _path: &Path) -> Box<dyn FileSystem> {
+        unimplemented!()
+    }
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+    fn read(&self, path: &Path, f: &mut dyn FnMut(&mut dyn filesystem::ReadSeek) -> IoResult<()>) -> IoResult<()> {
+        self.0.read(path, f)
+    }
+    fn write(&self, _path: &Path, _f: &mut dyn FnMut(&mut dyn std::io::Write) -> IoResult<()>) -> IoResult<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read only"))
+    }
+    fn create(&self, _path: &Path) -> IoResult<Box<dyn std::io::Write + Send>> {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read only"))
+    }
+    fn rename(&self, _from: &Path, _to: &Path) -> IoResult<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read only"))
+    }
+    fn create_dir_all(&self, _path: &Path) -> IoResult<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read only"))
+    }
+    fn full_path_for(&self, path: &Path) -> PathBuf {
+        self.0.full_path_for(path)
+    }
+    fn files(&self) -> Vec<PathBuf> {
+        self.0.files()
+    }
+    fn remove(&self, path: &Path) -> IoResult<()> {
+        self.0.remove(path)
+    }
+}
+
+#[test]
+fn validate_reports_write_errors() {
+    use std::io::Write;
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(
+        top_fs.duplicate(),
+        Box::new(ReadOnlyFileSystem(top_fs.clone())),
+    );
+    {
+        let mut w = provider.text_writer("foo.txt");
+        let _ = write!(w, "hello world");
+    }
+    assert!(provider.text("bar.txt", "hello world").is_err());
+
+    let results = validate("hi", top_fs.duplicate(), provider, |_| true);
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].file_name, Path::new("foo.txt"));
+    assert!(matches!(results[0].kind, ResultKind::IoError(_)));
+    assert_eq!(results[1].file_name, Path::new("bar.txt"));
+    assert!(matches!(results[1].kind, ResultKind::IoError(_)));
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn image_write_errors_are_returned() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(
+        top_fs.duplicate(),
+        Box::new(ReadOnlyFileSystem(top_fs.clone())),
+    );
+    assert!(provider.rgb_image("rgb.png", ::image::RgbImage::new(2, 2)).is_err());
+    assert!(provider.rgba_image("rgba.png", ::image::RgbaImage::new(2, 2)).is_err());
+
+    let results = validate("hi", top_fs.duplicate(), provider, |_| true);
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|r| matches!(r.kind, ResultKind::IoError(_))));
+}
+
+/// Writes files, but can't move them into place.
+#[cfg(test)]
+#[derive(Clone)]
+struct NoRenameFileSystem(FakeFileSystem);
+
+#[cfg(test)]
+impl FileSystem for NoRenameFileSystem {
+    fn duplicate(&self) -> Box<dyn FileSystem> {
+        Box::new(self.clone())
+    }
+    fn subsystem(&self, _path: &Path) -> Box<dyn FileSystem> {
+        unimplemented!()
+    }
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+    fn read(&self, path: &Path, f: &mut dyn FnMut(&mut dyn filesystem::ReadSeek) -> IoResult<()>) -> IoResult<()> {
+        self.0.read(path, f)
+    }
+    fn write(&self, path: &Path, f: &mut dyn FnMut(&mut dyn std::io::Write) -> IoResult<()>) -> IoResult<()> {
+        self.0.write(path, f)
+    }
+    fn create(&self, path: &Path) -> IoResult<Box<dyn std::io::Write + Send>> {
+        self.0.create(path)
+    }
+    fn rename(&self, _from: &Path, _to: &Path) -> IoResult<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "can't rename"))
+    }
+    fn create_dir_all(&self, path: &Path) -> IoResult<()> {
+        self.0.create_dir_all(path)
+    }
+    fn full_path_for(&self, path: &Path) -> PathBuf {
+        self.0.full_path_for(path)
+    }
+    fn files(&self) -> Vec<PathBuf> {
+        self.0.files()
+    }
+    fn remove(&self, path: &Path) -> IoResult<()> {
+        self.0.remove(path)
+    }
+}
+
+#[test]
+fn validate_reports_errors_moving_files_into_place() {
+    let top_fs = filesystem::FakeFileSystem::new();
+    let provider = provider::Provider::new(
+        top_fs.duplicate(),
+        Box::new(NoRenameFileSystem(top_fs.clone())),
+    );
+    // Both the error returned by `finish` and the one from dropping the
+    // writer are reported, even though neither is looked at here.
+    let _ = provider.text("foo.txt", "hello world");
+    {
+        let mut w = provider.text_writer("bar.txt");
+        write!(w, "hello world").unwrap();
+    }
+
+    let results = validate("hi", top_fs.duplicate(), provider, |_| true);
+    assert_eq!(results.len(), 2, "{:?}", results);
+    for (result, file) in results.iter().zip(["foo.txt", "bar.txt"]) {
+        assert_eq!(result.file_name, Path::new(file));
+        assert!(matches!(&result.kind, ResultKind::IoError(e) if e.contains("can't rename")), "{:?}", result);
+    }
+}
+
+#[test]
+fn validate_reports_unexpected_files() {
+    let results = difftest_validate("hi", |p| {
+        p.text("foo.txt", "foo").unwrap();
+        p.fs.write(Path::new("stray.txt"), &mut |w| w.write_all(b"stray"))
+            .unwrap();
+    });
+    assert_eq!(
+        results,
+        vec![
+            EResult::unexpected_file("hi", "stray.txt", "/actual/hi/stray.txt"),
+            EResult::expected_not_found("hi", "foo.txt", "/actual/hi/foo.txt", "/expected/hi/foo.txt"),
+        ]
+    );
+}
+
+#[test]
+fn directory_snapshots_compare_every_file_in_the_directory() {
+    let (mut results, _) = difftest_validate_fs("hi", |p| {
+        let expected = p.root_fs.subsystem(Path::new("expected/hi/out"));
+        expected.write(Path::new("same.txt"), &mut |w| w.write_all(b"same")).unwrap();
+        expected.write(Path::new("changed.txt"), &mut |w| w.write_all(b"old")).unwrap();
+        expected.write(Path::new("removed.txt"), &mut |w| w.write_all(b"gone")).unwrap();
+
+        let out = p.subdir("out");
+        assert_eq!(out.directory_snapshot(), Path::new("/actual/hi/out"));
+        out.fs.write(Path::new("same.txt"), &mut |w| w.write_all(b"same")).unwrap();
+        out.fs.write(Path::new("changed.txt"), &mut |w| w.write_all(b"new")).unwrap();
+        out.fs.write(Path::new("nested/added.txt"), &mut |w| w.write_all(b"new")).unwrap();
+    });
+    results.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    assert_eq!(
+        results,
+        vec![
+            EResult::difference(
+                "hi",
+                "out/changed.txt",
+                "/actual/hi/out/changed.txt",
+                "/expected/hi/out/changed.txt",
+                vec![],
+            ),
+            EResult::expected_not_found(
+                "hi",
+                "out/nested/added.txt",
+                "/actual/hi/out/nested/added.txt",
+                "/expected/hi/out/nested/added.txt",
+            ),
+            EResult::actual_not_found(
+                "hi",
+                "out/removed.txt",
+                "/actual/hi/out/removed.txt",
+                "/expected/hi/out/removed.txt",
+            ),
+            EResult::ok("hi", "out/same.txt"),
+        ]
+    );
+}
+
+#[test]
+fn clear_removes_files_from_earlier_runs() {
+    let fs = filesystem::FakeFileSystem::new();
+    fs.write(Path::new("actual/hi/stale.txt"), &mut |w| w.write_all(b"stale")).unwrap();
+    fs.write(Path::new("actual/other/kept.txt"), &mut |w| w.write_all(b"kept")).unwrap();
+    clear(&*fs.subsystem(Path::new("actual/hi")));
+    assert_eq!(fs.files(), vec![PathBuf::from("actual/other/kept.txt")]);
+}
+
+#[test]
+fn snapshot_dir_reports_each_file_and_the_tree() {
+    let temp = TempDir::new("dir");
+    let source = &temp.0;
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("a.txt"), "new").unwrap();
+    std::fs::write(source.join("sub/b.bin"), [0u8, 1]).unwrap();
+
+    let (results, fs) = difftest_validate_fs("hi", |p| {
+        let expected = p.root_fs.subsystem(Path::new("expected/hi"));
+        expected.write(Path::new("out/a.txt"), &mut |w| w.write_all(b"old")).unwrap();
+        expected.write(Path::new("out/gone.txt"), &mut |w| w.write_all(b"gone")).unwrap();
+        expected.write(Path::new("out.tree"), &mut |w| w.write_all(b"a.txt\ngone.txt\n")).unwrap();
+        p.snapshot_dir("out", source).unwrap();
+    });
+
+    let mut kinds: Vec<_> = results
+        .iter()
+        .map(|r| {
+            let kind = match r.kind {
+                ResultKind::Difference(_) => "changed",
+                ResultKind::ExpectedNotFound(_) => "added",
+                ResultKind::ActualNotFound(_) => "removed",
+                _ => "other",
+            };
+            (r.file_name.to_string_lossy().into_owned(), kind)
+        }).collect();
+    kinds.sort();
+    assert_eq!(
+        kinds,
+        vec![
+            ("out.tree".into(), "changed"),
+            ("out/a.txt".into(), "changed"),
+            ("out/gone.txt".into(), "removed"),
+            ("out/sub/b.bin".into(), "added"),
+        ]
+    );
+    assert_eq!(read_to_string(&fs, "actual/hi/out.tree"), "a.txt\nsub/b.bin\n");
+}
+
+#[test]
+fn path_for_registers_files_written_by_other_means() {
+    let results = difftest_validate("hi", |p| {
+        let path = p
+            .subdir("out")
+            .path_for(
+                "report.txt",
+                |a, b| byte_for_byte_equality(a, b),
+                |a, b, c, d| byte_for_byte_diff(a, b, c, d),
+            )
+            .unwrap();
+        assert_eq!(path, Path::new("/actual/hi/out/report.txt"));
+        p.fs.write(Path::new("out/report.txt"), &mut |w| w.write_all(b"report"))
+            .unwrap();
+    });
+    assert_eq!(
+        results,
+        vec![EResult::expected_not_found(
+            "hi",
+            "out/report.txt",
+            "/actual/hi/out/report.txt",
+            "/expected/hi/out/report.txt",
+        )]
+    );
+}
+
+#[cfg(all(feature = "command", unix))]
+#[test]
+fn command_output_is_normalized_and_snapshotted() {
+    let dir = std::env::temp_dir().canonicalize().unwrap();
+    let (results, fs) = difftest_validate_fs("hi", |p| {
+        let mut command = std::process::Command::new("sh");
+        command
+            .args(["-c", "echo \"$PWD says $GREETING\"; echo oops >&2; exit 3"])
+            .current_dir(&dir)
+            .env("GREETING", "hello");
+        let normalization = CommandNormalization {
+            cwd: true,
+            env: vec!["GREETING".into()],
+        };
+        p.command_with_normalization("greet", command, &normalization).unwrap();
+    });
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| matches!(r.kind, ResultKind::ExpectedNotFound(_))));
+    assert_eq!(read_to_string(&fs, "actual/hi/greet/stdout.txt"), "[CWD] says [$GREETING]\n");
+    assert_eq!(read_to_string(&fs, "actual/hi/greet/stderr.txt"), "oops\n");
+    assert_eq!(read_to_string(&fs, "actual/hi/greet/status.txt"), "exit code: 3\n");
+}
+
+#[cfg(all(feature = "command", unix))]
+#[test]
+fn relative_command_directories_are_normalized() {
+    let (results, fs) = difftest_validate_fs("hi", |p| {
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "pwd -P"]).current_dir("src");
+        let normalization = CommandNormalization { cwd: true, env: vec![] };
+        p.command_with_normalization("pwd", command, &normalization).unwrap();
+    });
+    assert_eq!(results.len(), 3);
+    assert_eq!(read_to_string(&fs, "actual/hi/pwd/stdout.txt"), "[CWD]\n");
+}
@@ -0,0 +1,32 @@
+//! Compiles and runs tests written with `#[expectation_test]`.  They don't
+//! write any files, so they pass without snapshots.
+
+use expectation::Provider;
+use expectation_plugin::expectation_test;
+
+#[expectation_test]
+fn sync_test(provider: Provider) {
+    let _ = provider.subdir("sync");
+}
+
+#[expectation_test]
+async fn builtin_async_test(provider: Provider) {
+    let _ = provider.subdir("builtin");
+    ::std::future::ready(()).await;
+}
+
+#[expectation_test(runtime = "builtin")]
+async fn explicit_builtin_async_test(provider: Provider) {
+    let _ = provider.subdir("builtin");
+    ::std::future::ready(()).await;
+}
+
+#[expectation_test(runtime = "tokio")]
+async fn tokio_async_test(provider: Provider) {
+    // Panics unless the test is driven by a tokio runtime.
+    let _ = ::tokio::runtime::Handle::current();
+    ::tokio::spawn(async move {
+        let _ = provider.subdir("tokio");
+    }).await
+    .unwrap();
+}